@@ -46,6 +46,26 @@ document.addEventListener("htmx:afterRequest", e => {
     if (trigger.id === "task") {
         showToast("Csengetés hozzáadva", "success");
     }
+    // Task edit
+    else if (trigger.id === "task-save") {
+        showToast("Csengetés módosítva", "success");
+    }
+    // Calendar entry
+    else if (trigger.id === "calendar-form") {
+        showToast("Naptár frissítve", "success");
+    }
+    // Bell schedule profiles
+    else if (["profile-form", "override-form"].includes(trigger.id) || trigger.classList?.contains("activate")) {
+        showToast("Csengetési rend frissítve", "success");
+    }
+    // Playlists
+    else if (trigger.id === "playlist-form" || trigger.classList?.contains("playlist-item-form")) {
+        showToast("Lejátszási lista frissítve", "success");
+    }
+    // Exception of a single occurrence
+    else if (trigger.classList?.contains("exception-form")) {
+        showToast("Kivétel hozzáadva", "success");
+    }
     // File upload
     else if (trigger.id === "fileupload") {
         showToast("File feltöltve", "success");
@@ -56,15 +76,34 @@ document.addEventListener("htmx:afterRequest", e => {
     else if (trigger.classList?.contains("delete")) {
         showToast("Törölve", "success");
     }
+    // Pausing all schedules
+    else if (trigger.id === "pause-form") {
+        showToast("Csengetések szüneteltetve", "success");
+    }
+    else if (trigger.id === "btn-resume") {
+        showToast("Csengetések folytatva", "success");
+    }
+    // Master volume
+    else if (trigger.id === "volume") {
+        showToast("Hangerő beállítva", "success");
+    }
+    // Loudness normalization
+    else if (trigger.id === "loudness") {
+        showToast("Célhangosság beállítva", "success");
+    }
+    // Gain of a file
+    else if (trigger.name === "gain") {
+        showToast("Erősítés beállítva", "success");
+    }
     // Stop button
     else if (trigger.id === "btn-stop") {
         showToast("Leállítva", "success");
     }
 });
 
-htmx.on("form#task", "htmx:configRequest", e => {
-    if (e.detail.elt.id !== "task") return;
-    e.detail.parameters["priority"] ??= 0;
+// sent by the server when a new task overlaps with others
+document.addEventListener("conflict", () => {
+    showToast("Figyelem: ütközik egy másik csengetéssel, lásd az előnézetet", "error");
 });
 
 htmx.on("#fileupload", "htmx:xhr:progress", function(e) {
@@ -0,0 +1,410 @@
+// outputs without a sound card, for tests, CI and servers without sound.
+// selected by the device of an output: `null:` discards the audio, `wav:DIR` records it
+use crate::sink::Output;
+use chrono::Local;
+use rodio::Source;
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+const NULL_PREFIX: &str = "null:";
+const WAV_PREFIX: &str = "wav:";
+/// how much is played at once, before waiting for the clock to catch up
+const CHUNK: f64 = 0.01;
+
+/// where an output plays
+#[derive(Debug, Clone, PartialEq)]
+pub enum Backend {
+    /// a sound card, `None` for the default one
+    Device(Option<String>),
+    /// plays in real time, but the audio is thrown away
+    Null,
+    /// plays in real time, and writes every track to a file in the directory
+    Wav(PathBuf),
+}
+impl Backend {
+    pub fn parse(device: Option<&str>) -> Backend {
+        match device {
+            Some(d) if d == NULL_PREFIX => Backend::Null,
+            Some(d) => match d.strip_prefix(WAV_PREFIX) {
+                Some(dir) => Backend::Wav(dir.into()),
+                None => Backend::Device(Some(d.to_owned())),
+            },
+            None => Backend::Device(None),
+        }
+    }
+}
+
+/// plays an output on a thread of its own, stops when dropped
+pub struct Headless {
+    stop: Arc<AtomicBool>,
+}
+impl Headless {
+    /// `dir` is where to record, `None` to discard the audio
+    pub fn start(name: &str, src: Output, dir: Option<PathBuf>) -> anyhow::Result<Headless> {
+        if let Some(dir) = &dir {
+            fs::create_dir_all(dir)?;
+        }
+        let stop = Arc::new(AtomicBool::new(false));
+        let s = stop.clone();
+        let name = name.to_owned();
+        thread::Builder::new()
+            .name(format!("headless-{name}"))
+            .spawn(move || play(src, &name, dir, &s))?;
+        Ok(Headless { stop })
+    }
+}
+impl Drop for Headless {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+/// take samples at the pace a sound card would
+fn play(mut src: Output, output: &str, dir: Option<PathBuf>, stop: &AtomicBool) {
+    let start = Instant::now();
+    // in seconds
+    let mut played = 0.0;
+    let mut switches = src.switches();
+    let mut wav: Option<WavWriter> = None;
+
+    while !stop.load(Ordering::Relaxed) {
+        let chunk_end = played + CHUNK;
+        while played < chunk_end {
+            let Some(sample) = src.next() else {
+                return;
+            };
+            // a new track started with this sample
+            if src.switches() != switches {
+                switches = src.switches();
+                wav = None;
+                if let (Some(dir), Some(name)) = (&dir, src.track_name()) {
+                    wav = WavWriter::create(&dir.join(file_name(output, name)), &src)
+                        .inspect_err(|e| error!("{output}: failed to record {name:?}: {e}"))
+                        .ok();
+                }
+            }
+            if let Some(w) = &mut wav {
+                if let Err(e) = w.write(sample) {
+                    error!("{output}: failed to record: {e}");
+                    wav = None;
+                }
+            }
+            played += 1.0 / (src.sample_rate() as f64 * src.channels() as f64);
+        }
+        thread::sleep(
+            (start + Duration::from_secs_f64(played)).saturating_duration_since(Instant::now()),
+        );
+    }
+}
+
+/// when the track started, on which output, and what it is
+fn file_name(output: &str, track: &str) -> String {
+    let track: String = track
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' => c,
+            _ => '_',
+        })
+        .collect();
+    format!(
+        "{}_{output}_{track}.wav",
+        Local::now().format("%Y-%m-%dT%H-%M-%S%.3f")
+    )
+}
+
+/// 32-bit float samples, the sizes are filled in when it's dropped
+struct WavWriter {
+    file: BufWriter<File>,
+    samples: u32,
+}
+impl WavWriter {
+    fn create<S: Source>(path: &Path, src: &S) -> io::Result<WavWriter>
+    where
+        S::Item: rodio::Sample,
+    {
+        let (channels, rate) = (src.channels(), src.sample_rate());
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(b"RIFF")?;
+        file.write_all(&0u32.to_le_bytes())?;
+        file.write_all(b"WAVE")?;
+        file.write_all(b"fmt ")?;
+        file.write_all(&16u32.to_le_bytes())?;
+        file.write_all(&3u16.to_le_bytes())?; // IEEE float
+        file.write_all(&channels.to_le_bytes())?;
+        file.write_all(&rate.to_le_bytes())?;
+        file.write_all(&(rate * channels as u32 * 4).to_le_bytes())?;
+        file.write_all(&(channels * 4).to_le_bytes())?;
+        file.write_all(&32u16.to_le_bytes())?;
+        file.write_all(b"data")?;
+        file.write_all(&0u32.to_le_bytes())?;
+        Ok(WavWriter { file, samples: 0 })
+    }
+
+    fn write(&mut self, sample: f32) -> io::Result<()> {
+        self.file.write_all(&sample.to_le_bytes())?;
+        self.samples += 1;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        let data = self.samples * 4;
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file.write_all(&(36 + data).to_le_bytes())?;
+        self.file.seek(SeekFrom::Start(40))?;
+        self.file.write_all(&data.to_le_bytes())?;
+        self.file.flush()
+    }
+}
+impl Drop for WavWriter {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            error!("failed to finish recording: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::{Controller, Track};
+    use rodio::source::SineWave;
+
+    #[test]
+    fn parses_devices() {
+        assert_eq!(Backend::parse(None), Backend::Device(None));
+        assert_eq!(
+            Backend::parse(Some("USB Audio")),
+            Backend::Device(Some("USB Audio".into()))
+        );
+        assert_eq!(Backend::parse(Some("null:")), Backend::Null);
+        assert_eq!(
+            Backend::parse(Some("wav:/tmp/csengo")),
+            Backend::Wav("/tmp/csengo".into())
+        );
+    }
+
+    /// removes the directory when it's dropped, even if an assert fails
+    struct TempDir(PathBuf);
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn records_tracks() {
+        let dir = TempDir(std::env::temp_dir().join(format!("csengo-test-{}", std::process::id())));
+        let c = Controller::init("test".into(), Some(format!("wav:{}", dir.0.display()))).unwrap();
+        c.append(Track {
+            id: 0,
+            src: Box::new(SineWave::new(440.0).take_duration(Duration::from_millis(100))),
+            name: Some("beep".into()),
+            start: None,
+            task: None,
+            playlist: None,
+            urgency: crate::Urgency::Normal,
+            played: Duration::ZERO,
+        });
+        // the silence before it, and the track itself
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        c.set_device(Some(NULL_PREFIX.into())).unwrap();
+
+        let files: Vec<_> = fs::read_dir(&dir.0)
+            .unwrap()
+            .map(|f| f.unwrap().path())
+            .collect();
+        assert_eq!(files.len(), 1);
+        assert!(files[0].to_string_lossy().ends_with("_test_beep.wav"));
+        let wav = fs::read(&files[0]).unwrap();
+        let data = u32::from_le_bytes(wav[40..44].try_into().unwrap());
+        // 100ms of mono 48kHz, 4 bytes per sample
+        assert!((data / 4).abs_diff(4800) <= 1);
+        assert_eq!(wav.len(), 44 + data as usize);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reorder_keeps_urgent_first() {
+        let c = Controller::init("test".into(), Some(NULL_PREFIX.into())).unwrap();
+        // not due yet, so they stay in the queue
+        let start = Instant::now() + Duration::from_secs(60);
+        for (name, urgency) in [
+            ("bell", crate::Urgency::Duck),
+            ("a", crate::Urgency::Normal),
+            ("b", crate::Urgency::Normal),
+        ] {
+            c.append(Track {
+                id: 0,
+                src: Box::new(SineWave::new(440.0).take_duration(Duration::from_secs(1))),
+                name: Some(name.into()),
+                start: Some(start),
+                task: None,
+                playlist: None,
+                urgency,
+                played: Duration::ZERO,
+            });
+        }
+        let ids: Vec<_> = c.queue(Local::now()).iter().map(|t| t.id).collect();
+        let names =
+            || -> Vec<String> { c.queue(Local::now()).into_iter().map(|t| t.name).collect() };
+
+        assert!(c.reorder(ids[2], 0));
+        assert_eq!(names(), ["bell", "b", "a"]);
+        assert!(c.reorder(ids[0], 5));
+        assert_eq!(names(), ["bell", "b", "a"]);
+        assert!(c.reorder(ids[1], 1));
+        assert_eq!(names(), ["bell", "a", "b"]);
+        assert!(!c.reorder(u64::MAX, 0));
+        c.stop();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn pauses_tracks() {
+        let c = Controller::init("test".into(), Some(NULL_PREFIX.into())).unwrap();
+        c.append(Track {
+            id: 0,
+            src: Box::new(SineWave::new(440.0).take_duration(Duration::from_secs(5))),
+            name: Some("beep".into()),
+            start: None,
+            task: None,
+            playlist: None,
+            urgency: crate::Urgency::Normal,
+            played: Duration::ZERO,
+        });
+        // the silence before it, and a bit of the track
+        tokio::time::sleep(Duration::from_millis(800)).await;
+        c.pause();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let held = c
+            .np_rx()
+            .borrow()
+            .as_ref()
+            .and_then(|np| np.paused)
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let np = c.np_rx().borrow().clone().unwrap();
+        assert_eq!(np.paused, Some(held));
+        assert_eq!(np.elapsed(), held);
+
+        c.resume();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let np = c.np_rx().borrow().clone().unwrap();
+        assert_eq!(np.paused, None);
+        // it goes on from where it was
+        assert!(np.elapsed() > held && np.elapsed() < held + Duration::from_millis(500));
+        c.stop();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn plays_urgent_tracks_over_others() {
+        use crate::Urgency;
+        let tone = |name: &str, len: u64, urgency| Track {
+            id: 0,
+            src: Box::new(SineWave::new(440.0).take_duration(Duration::from_millis(len))),
+            name: Some(name.into()),
+            start: None,
+            task: None,
+            playlist: None,
+            urgency,
+            played: Duration::ZERO,
+        };
+        for urgency in [
+            Urgency::Duck,
+            Urgency::Interrupt { resume: false },
+            Urgency::Interrupt { resume: true },
+        ] {
+            let c = Controller::init("test".into(), Some(NULL_PREFIX.into())).unwrap();
+            c.append(tone("music", 5000, Urgency::Normal));
+            tokio::time::sleep(Duration::from_millis(800)).await;
+            let before = c.np_rx().borrow().as_ref().unwrap().elapsed();
+
+            c.append(tone("bell", 300, urgency));
+            tokio::time::sleep(Duration::from_millis(150)).await;
+            let np = c.np_rx().borrow().clone().unwrap();
+            assert_eq!(np.name, "bell", "{urgency:?}");
+            assert_eq!(np.urgency, urgency);
+            let under = match urgency {
+                Urgency::Interrupt { resume: false } => None,
+                _ => Some("music".to_string()),
+            };
+            assert_eq!(np.under, under, "{urgency:?}");
+
+            tokio::time::sleep(Duration::from_millis(400)).await;
+            let np = c.np_rx().borrow().clone();
+            match urgency {
+                // it played on under the bell
+                Urgency::Duck => {
+                    let np = np.unwrap();
+                    assert_eq!(np.name, "music");
+                    assert!(np.elapsed() >= before + Duration::from_millis(400));
+                }
+                Urgency::Interrupt { resume: false } => assert!(np.is_none()),
+                // it goes on from where it was cut
+                _ => {
+                    let np = np.unwrap();
+                    assert_eq!(np.name, "music");
+                    assert_eq!(np.under, None);
+                    assert!(np.elapsed() >= before);
+                    assert!(np.elapsed() < before + Duration::from_millis(400));
+                }
+            }
+            c.stop();
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn planned_tracks_cut_through_pauses() {
+        let c = Controller::init("test".into(), Some(NULL_PREFIX.into())).unwrap();
+        c.append(Track {
+            id: 0,
+            src: Box::new(SineWave::new(440.0).take_duration(Duration::from_secs(5))),
+            name: Some("beep".into()),
+            start: None,
+            task: None,
+            playlist: None,
+            urgency: crate::Urgency::Normal,
+            played: Duration::ZERO,
+        });
+        tokio::time::sleep(Duration::from_millis(800)).await;
+        c.pause();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let held = c
+            .np_rx()
+            .borrow()
+            .as_ref()
+            .and_then(|np| np.paused)
+            .unwrap();
+
+        c.append(Track {
+            id: 0,
+            src: Box::new(SineWave::new(880.0).take_duration(Duration::from_millis(300))),
+            name: Some("bell".into()),
+            start: Some(Instant::now()),
+            task: None,
+            playlist: None,
+            urgency: crate::Urgency::Normal,
+            played: Duration::ZERO,
+        });
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        let np = c.np_rx().borrow().clone().unwrap();
+        assert_eq!(np.name, "bell");
+        let names: Vec<_> = c.queue(Local::now()).into_iter().map(|t| t.name).collect();
+        assert_eq!(names, ["beep"]);
+
+        // the paused track goes on after it, from where it was
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        let np = c.np_rx().borrow().clone().unwrap();
+        assert_eq!(np.name, "beep");
+        assert_eq!(np.paused, None);
+        assert!(np.elapsed() > held && np.elapsed() < held + Duration::from_millis(500));
+        c.stop();
+    }
+}
@@ -0,0 +1,105 @@
+// school calendar: date ranges on which recurring bells don't ring
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr};
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum Kind {
+    /// national holidays
+    Holiday,
+    /// school breaks
+    Break,
+    /// special days without bells, eg. exam days
+    Special,
+}
+impl Kind {
+    pub const ALL: [Kind; 3] = [Kind::Holiday, Kind::Break, Kind::Special];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Kind::Holiday => "holiday",
+            Kind::Break => "break",
+            Kind::Special => "special",
+        }
+    }
+    pub fn label(&self) -> &'static str {
+        match self {
+            Kind::Holiday => "Ünnepnap",
+            Kind::Break => "Szünet",
+            Kind::Special => "Tanítás nélküli nap",
+        }
+    }
+}
+impl FromStr for Kind {
+    type Err = InvalidKind;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Kind::ALL
+            .into_iter()
+            .find(|k| k.as_str() == s)
+            .ok_or_else(|| InvalidKind(s.to_owned()))
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidKind(String);
+impl fmt::Display for InvalidKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid calendar entry kind: {}", self.0)
+    }
+}
+impl std::error::Error for InvalidKind {}
+
+/// a range of days, both ends inclusive
+#[derive(Debug, Clone, Serialize)]
+pub struct Entry {
+    pub id: i64,
+    pub kind: Kind,
+    pub name: String,
+    pub start: NaiveDate,
+    pub end: NaiveDate,
+}
+
+/// the yearly school calendar, as published by the ministry
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SchoolYear {
+    #[serde(default)]
+    pub holidays: Vec<Range>,
+    #[serde(default)]
+    pub breaks: Vec<Range>,
+    #[serde(default)]
+    pub special_days: Vec<Range>,
+}
+impl SchoolYear {
+    pub fn into_entries(self) -> Vec<Entry> {
+        [
+            (Kind::Holiday, self.holidays),
+            (Kind::Break, self.breaks),
+            (Kind::Special, self.special_days),
+        ]
+        .into_iter()
+        .flat_map(|(kind, ranges)| ranges.into_iter().map(move |r| r.into_entry(kind)))
+        .collect()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Range {
+    pub name: String,
+    pub start: NaiveDate,
+    /// single day if missing
+    pub end: Option<NaiveDate>,
+}
+impl Range {
+    fn into_entry(self, kind: Kind) -> Entry {
+        Entry {
+            id: 0,
+            kind,
+            name: self.name,
+            start: self.start,
+            end: self.end.unwrap_or(self.start),
+        }
+    }
+}
@@ -0,0 +1,75 @@
+// the source of time for the scheduler, so tests can control it
+use chrono::{DateTime, Local};
+use futures_util::future::BoxFuture;
+use std::time::Duration;
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Local>;
+    /// wait until `d` has passed on this clock
+    fn sleep(&self, d: Duration) -> BoxFuture<'static, ()>;
+}
+
+/// the real time
+pub struct SystemClock;
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Local> {
+        Local::now()
+    }
+    fn sleep(&self, d: Duration) -> BoxFuture<'static, ()> {
+        Box::pin(tokio::time::sleep(d))
+    }
+}
+
+/// a clock that only moves when told to
+#[cfg(test)]
+pub struct SimulatedClock {
+    now: tokio::sync::watch::Sender<DateTime<Local>>,
+}
+#[cfg(test)]
+impl SimulatedClock {
+    pub fn new(now: DateTime<Local>) -> Self {
+        Self {
+            now: tokio::sync::watch::channel(now).0,
+        }
+    }
+    pub fn advance(&self, d: chrono::Duration) {
+        self.now.send_modify(|now| *now += d);
+    }
+}
+#[cfg(test)]
+impl Clock for SimulatedClock {
+    fn now(&self) -> DateTime<Local> {
+        *self.now.borrow()
+    }
+    fn sleep(&self, d: Duration) -> BoxFuture<'static, ()> {
+        let mut rx = self.now.subscribe();
+        let until = chrono::Duration::from_std(d)
+            .ok()
+            .and_then(|d| self.now().checked_add_signed(d));
+        Box::pin(async move {
+            match until {
+                Some(until) => _ = rx.wait_for(|now| *now >= until).await,
+                None => std::future::pending().await,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::FutureExt;
+
+    #[tokio::test]
+    async fn simulated_sleep() {
+        let clock = SimulatedClock::new(Local::now());
+        let mut sleep = clock.sleep(Duration::from_secs(60));
+
+        clock.advance(chrono::Duration::seconds(59));
+        tokio::task::yield_now().await;
+        assert!((&mut sleep).now_or_never().is_none());
+
+        clock.advance(chrono::Duration::seconds(1));
+        assert!(sleep.now_or_never().is_some());
+    }
+}
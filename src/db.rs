@@ -1,7 +1,18 @@
-use crate::{metrics as m, player::Player, scheduler::schedule, File, Task};
-use chrono::{Local, NaiveTime};
-use rusqlite::{params, Connection, Error, Result, Row};
-use std::{path::Path, sync::Arc, time::Instant};
+use crate::{
+    calendar::Entry,
+    metrics as m,
+    player::{measure, track_length, Player},
+    scheduler::{catch_up, schedule, spawn_resume},
+    zones, Action, Anchor, Exception, Fade, File, FileInfo, Loudness, Missed, Playlist,
+    PlaylistItem, Task, Urgency,
+};
+use chrono::{DateTime, Local, NaiveDate, NaiveTime, Weekday};
+use rusqlite::{params, types::Type, Connection, Error, Result, Row};
+use std::{
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use tokio::sync::Mutex;
 
 pub type Db = Arc<Mutex<Connection>>;
@@ -9,23 +20,95 @@ pub type Db = Arc<Mutex<Connection>>;
 const DB_FILE: &str = "./csengo.db";
 
 /// to be incremented on schema changes
-pub const DB_VERSION: u32 = 1;
+pub const DB_VERSION: u32 = 15;
 
 /// this initializes the db to the latest schema version
 const CREATE_TABLES: &str = "
 CREATE TABLE tasks (
     type      TEXT NOT NULL,
     name      TEXT PRIMARY KEY,
-    priority  INTEGER NOT NULL,
+    zones     TEXT NOT NULL,
     file_name TEXT NOT NULL,
-    time      TEXT
+    time      TEXT,
+    days      TEXT,
+    profile   TEXT,
+    missed    TEXT NOT NULL DEFAULT 'drop',
+    grace     INTEGER,
+    last_run  TEXT,
+    valid_from  TEXT,
+    valid_until TEXT,
+    archived  INTEGER NOT NULL DEFAULT 0,
+    anchor    TEXT,
+    anchor_offset INTEGER,
+    gain      REAL NOT NULL DEFAULT 0,
+    fade_in   REAL NOT NULL DEFAULT 0,
+    fade_out  REAL NOT NULL DEFAULT 0,
+    urgency   TEXT NOT NULL DEFAULT 'normal'
 ), STRICT;
 CREATE TABLE files (
     name      TEXT PRIMARY KEY,
-    data      BLOB
+    data      BLOB,
+    duration  REAL,
+    gain      REAL NOT NULL DEFAULT 0,
+    loudness  REAL,
+    true_peak REAL
+), STRICT;
+CREATE TABLE calendar (
+    id        INTEGER PRIMARY KEY,
+    kind      TEXT NOT NULL,
+    name      TEXT NOT NULL,
+    start     TEXT NOT NULL,
+    end       TEXT NOT NULL
+), STRICT;
+CREATE TABLE profiles (
+    name      TEXT PRIMARY KEY
+), STRICT;
+CREATE TABLE profile_overrides (
+    date      TEXT PRIMARY KEY,
+    profile   TEXT NOT NULL
+), STRICT;
+CREATE TABLE settings (
+    key       TEXT PRIMARY KEY,
+    value     TEXT NOT NULL
+), STRICT;
+CREATE TABLE exceptions (
+    id        INTEGER PRIMARY KEY,
+    task      TEXT NOT NULL,
+    date      TEXT NOT NULL,
+    time      TEXT NOT NULL,
+    action    TEXT NOT NULL,
+    new_time  TEXT,
+    file_name TEXT,
+    UNIQUE (task, date, time)
+), STRICT;
+CREATE TABLE playlists (
+    name      TEXT PRIMARY KEY
+), STRICT;
+CREATE TABLE playlist_items (
+    id        INTEGER PRIMARY KEY,
+    playlist  TEXT NOT NULL,
+    file_name TEXT NOT NULL,
+    gap       REAL NOT NULL DEFAULT 0
 ), STRICT;
 ";
 
+// settings keys
+const ACTIVE_PROFILE: &str = "active_profile";
+const PAUSED_UNTIL: &str = "paused_until";
+/// how rusqlite saves a `DateTime`, which is how it was saved before
+const PAUSED_UNTIL_FMT: &str = "%F %T%.f%:z";
+const VOLUME: &str = "volume";
+const TARGET_LOUDNESS: &str = "target_loudness";
+const STOP_FADE: &str = "stop_fade";
+/// followed by the name of the output
+const OUTPUT_DEVICE: &str = "device.";
+
+/// seconds
+pub const DEFAULT_STOP_FADE: f32 = 0.5;
+
+/// LUFS, files are normalized to this unless it's turned off
+pub const DEFAULT_TARGET_LOUDNESS: f64 = -16.0;
+
 // the format of recurring times in the db
 pub static TIMEFMT: &str = "%H:%M";
 
@@ -50,21 +133,198 @@ pub fn init() -> Result<(Db, bool)> {
     Ok((Arc::new(Mutex::new(conn)), db_new))
 }
 
+/// an empty db that only lives in memory
+#[cfg(test)]
+pub fn memory() -> Result<Db> {
+    let conn = Connection::open_in_memory()?;
+    conn.execute_batch(CREATE_TABLES)?;
+    Ok(Arc::new(Mutex::new(conn)))
+}
+
 fn migrate(conn: &Connection, version: u32) -> Result<()> {
     info!("existing db at v{version} (latest: v{DB_VERSION}), running migrations");
 
     for i in version..DB_VERSION {
         match i {
             0 => {
-                conn.execute_batch(&format!(
+                conn.execute_batch(
                     "BEGIN EXCLUSIVE;
                      ALTER TABLE tasks RENAME TO old_tasks;
                      ALTER TABLE files RENAME TO old_files;
-                     {CREATE_TABLES}
+                     CREATE TABLE tasks (
+                         type      TEXT NOT NULL,
+                         name      TEXT PRIMARY KEY,
+                         priority  INTEGER NOT NULL,
+                         file_name TEXT NOT NULL,
+                         time      TEXT
+                     ), STRICT;
+                     CREATE TABLE files (
+                         name      TEXT PRIMARY KEY,
+                         data      BLOB
+                     ), STRICT;
                      INSERT INTO tasks SELECT *, 0 as priority FROM old_tasks;
                      INSERT INTO files SELECT * FROM old_files;
-                     COMMIT;"
-                ))?;
+                     COMMIT;",
+                )?;
+            }
+            1 => {
+                // recurring tasks used to ring every day
+                conn.execute_batch(
+                    "BEGIN EXCLUSIVE;
+                     ALTER TABLE tasks ADD COLUMN days TEXT;
+                     UPDATE tasks SET days = 'Mon;Tue;Wed;Thu;Fri;Sat;Sun' WHERE type == 'recurring';
+                     COMMIT;",
+                )?;
+            }
+            2 => {
+                conn.execute_batch(
+                    "CREATE TABLE calendar (
+                         id        INTEGER PRIMARY KEY,
+                         kind      TEXT NOT NULL,
+                         name      TEXT NOT NULL,
+                         start     TEXT NOT NULL,
+                         end       TEXT NOT NULL
+                     ), STRICT;",
+                )?;
+            }
+            3 => {
+                conn.execute_batch(
+                    "BEGIN EXCLUSIVE;
+                     ALTER TABLE tasks ADD COLUMN profile TEXT;
+                     CREATE TABLE profiles (
+                         name      TEXT PRIMARY KEY
+                     ), STRICT;
+                     CREATE TABLE profile_overrides (
+                         date      TEXT PRIMARY KEY,
+                         profile   TEXT NOT NULL
+                     ), STRICT;
+                     CREATE TABLE settings (
+                         key       TEXT PRIMARY KEY,
+                         value     TEXT NOT NULL
+                     ), STRICT;
+                     COMMIT;",
+                )?;
+            }
+            4 => {
+                conn.execute_batch(
+                    "CREATE TABLE exceptions (
+                         id        INTEGER PRIMARY KEY,
+                         task      TEXT NOT NULL,
+                         date      TEXT NOT NULL,
+                         time      TEXT NOT NULL,
+                         action    TEXT NOT NULL,
+                         new_time  TEXT,
+                         file_name TEXT,
+                         UNIQUE (task, date, time)
+                     ), STRICT;",
+                )?;
+            }
+            5 => {
+                conn.execute_batch(
+                    "BEGIN EXCLUSIVE;
+                     ALTER TABLE tasks ADD COLUMN missed TEXT NOT NULL DEFAULT 'drop';
+                     ALTER TABLE tasks ADD COLUMN grace INTEGER;
+                     ALTER TABLE tasks ADD COLUMN last_run TEXT;
+                     COMMIT;",
+                )?;
+            }
+            6 => {
+                // filled in lazily, see `get_file_duration`
+                conn.execute_batch("ALTER TABLE files ADD COLUMN duration REAL;")?;
+            }
+            7 => {
+                conn.execute_batch(
+                    "BEGIN EXCLUSIVE;
+                     CREATE TABLE playlists (
+                         name      TEXT PRIMARY KEY
+                     ), STRICT;
+                     CREATE TABLE playlist_items (
+                         id        INTEGER PRIMARY KEY,
+                         playlist  TEXT NOT NULL,
+                         file_name TEXT NOT NULL,
+                         gap       REAL NOT NULL DEFAULT 0
+                     ), STRICT;
+                     COMMIT;",
+                )?;
+            }
+            8 => {
+                conn.execute_batch(
+                    "BEGIN EXCLUSIVE;
+                     ALTER TABLE tasks ADD COLUMN valid_from TEXT;
+                     ALTER TABLE tasks ADD COLUMN valid_until TEXT;
+                     ALTER TABLE tasks ADD COLUMN archived INTEGER NOT NULL DEFAULT 0;
+                     COMMIT;",
+                )?;
+            }
+            9 => {
+                conn.execute_batch(
+                    "BEGIN EXCLUSIVE;
+                     ALTER TABLE tasks ADD COLUMN anchor TEXT;
+                     ALTER TABLE tasks ADD COLUMN anchor_offset INTEGER;
+                     COMMIT;",
+                )?;
+            }
+            10 => {
+                conn.execute_batch(
+                    "BEGIN EXCLUSIVE;
+                     ALTER TABLE tasks ADD COLUMN gain REAL NOT NULL DEFAULT 0;
+                     ALTER TABLE files ADD COLUMN gain REAL NOT NULL DEFAULT 0;
+                     COMMIT;",
+                )?;
+            }
+            11 => {
+                // filled in on startup, see `spawn_measure`
+                conn.execute_batch(
+                    "BEGIN EXCLUSIVE;
+                     ALTER TABLE files ADD COLUMN loudness REAL;
+                     ALTER TABLE files ADD COLUMN true_peak REAL;
+                     COMMIT;",
+                )?;
+            }
+            12 => {
+                // priority plays used to go to the right channel too,
+                // which is what the `priority` zone of the default config still does
+                let names = zones::names();
+                let has = |zone: &str| names.iter().any(|z| z == zone);
+                let tr = conn.unchecked_transaction()?;
+                tr.execute_batch("ALTER TABLE tasks ADD COLUMN zones TEXT NOT NULL DEFAULT '';")?;
+                let mut stmt = tr.prepare("SELECT name, priority FROM tasks")?;
+                let tasks = stmt.query_map((), |r| Ok((r.get::<_, String>(0)?, r.get(1)?)))?;
+                for task in tasks {
+                    let (name, priority): (String, i64) = task?;
+                    let wanted: &[&str] = match priority {
+                        0 => &["main"],
+                        _ => &["main", "priority"],
+                    };
+                    let mut routed: Vec<&str> =
+                        wanted.iter().copied().filter(|&z| has(z)).collect();
+                    if routed.len() < wanted.len() {
+                        if routed.is_empty() {
+                            routed.push(&names[0]);
+                        }
+                        warn!("{name:?} played in {wanted:?}, which aren't all configured zones, it plays in {routed:?} now");
+                    }
+                    tr.execute(
+                        "UPDATE tasks SET zones = ? WHERE name == ?",
+                        (routed.join(";"), &name),
+                    )?;
+                }
+                drop(stmt);
+                tr.execute_batch("ALTER TABLE tasks DROP COLUMN priority;")?;
+                tr.commit()?;
+            }
+            13 => {
+                conn.execute_batch(
+                    "BEGIN EXCLUSIVE;
+                     ALTER TABLE tasks ADD COLUMN fade_in REAL NOT NULL DEFAULT 0;
+                     ALTER TABLE tasks ADD COLUMN fade_out REAL NOT NULL DEFAULT 0;
+                     COMMIT;",
+                )?;
+            }
+            14 => {
+                conn.execute_batch(
+                    "ALTER TABLE tasks ADD COLUMN urgency TEXT NOT NULL DEFAULT 'normal';",
+                )?;
             }
             DB_VERSION.. => (),
         }
@@ -80,17 +340,34 @@ fn migrate(conn: &Connection, version: u32) -> Result<()> {
 
 pub async fn load(player: Player) -> anyhow::Result<usize> {
     let conn = &*player.conn.lock().await;
+
+    // exceptions for days gone by are useless
+    conn.execute(
+        "DELETE FROM exceptions WHERE date < ?",
+        (player.clock.now().date_naive(),),
+    )?;
+
+    // the pause may have ended while we were down
+    if let Some(until) = get_paused_until(conn)? {
+        m::set_paused(true);
+        spawn_resume(player.clone(), until);
+    }
+
+    spawn_measure(player.conn.clone());
+
     let tasks = list_tasks(conn)?;
     let mut len = tasks.len();
     for task in tasks {
         let name = task.get_name().to_owned();
 
-        if let Task::Scheduled { time, .. } = &task {
-            if *time < Local::now() {
-                warn!("{name}: Scheduled task missed, deleting it");
-                delete_task(conn, &name)?;
+        // plays missed while we were down
+        match catch_up(conn, &player, &task) {
+            Ok(true) => (),
+            Ok(false) => {
+                len -= 1;
                 continue;
             }
+            Err(e) => warn!("{name}: failed to catch up on missed plays\n{e:#?}"),
         }
 
         if let Err(e) = schedule(task, player.clone()).await {
@@ -103,8 +380,14 @@ pub async fn load(player: Player) -> anyhow::Result<usize> {
 
 pub fn insert_file(conn: &Connection, file: File) -> Result<()> {
     conn.execute(
-        "INSERT INTO files (name, data) VALUES (?1, ?2)",
-        params![file.name, file.data.to_vec()],
+        "INSERT INTO files (name, data, gain, loudness, true_peak) VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+            file.name,
+            file.data.to_vec(),
+            file.gain,
+            file.loudness.map(|l| l.integrated),
+            file.loudness.map(|l| l.true_peak)
+        ],
     )
     .map(|_| ())
 }
@@ -113,79 +396,725 @@ pub fn list_files(conn: &Connection) -> Result<Vec<String>> {
     let res = s.query_map([], |r| r.get(0))?;
     res.collect()
 }
+/// the files with their settings, without the data
+pub fn list_file_info(conn: &Connection) -> Result<Vec<FileInfo>> {
+    let mut s = conn.prepare("SELECT name, gain, loudness, true_peak FROM files")?;
+    let res = s.query_map([], |r| {
+        Ok(FileInfo {
+            name: r.get("name")?,
+            gain: r.get("gain")?,
+            loudness: parse_loudness(r)?,
+        })
+    })?;
+    res.collect()
+}
 pub fn get_file(conn: &Connection, name: &str) -> Result<File> {
     conn.query_row("SELECT * FROM files WHERE name == ?", (name,), |r| {
         Ok(File {
-            name: r.get(0)?,
-            data: r.get::<_, Vec<u8>>(1)?.into(),
+            name: r.get("name")?,
+            data: r.get::<_, Vec<u8>>("data")?.into(),
+            gain: r.get("gain")?,
+            loudness: parse_loudness(r)?,
         })
     })
 }
-pub fn delete_file(conn: &Connection, name: &str) -> Result<()> {
-    conn.execute("DELETE FROM files WHERE name == ?", (name,))
-        .map(|_| ())
+fn parse_loudness(r: &Row) -> Result<Option<Loudness>> {
+    Ok(match (r.get("loudness")?, r.get("true_peak")?) {
+        (Some(integrated), Some(true_peak)) => Some(Loudness {
+            integrated,
+            true_peak,
+        }),
+        _ => None,
+    })
+}
+pub fn set_file_loudness(conn: &Connection, name: &str, loudness: Loudness) -> Result<()> {
+    conn.execute(
+        "UPDATE files SET loudness = ?1, true_peak = ?2 WHERE name == ?3",
+        params![loudness.integrated, loudness.true_peak, name],
+    )
+    .map(|_| ())
 }
+fn list_unmeasured_files(conn: &Connection) -> Result<Vec<String>> {
+    let mut s = conn.prepare("SELECT name FROM files WHERE loudness IS NULL")?;
+    let res = s.query_map([], |r| r.get(0))?;
+    res.collect()
+}
+/// measure the files uploaded before their loudness was measured on upload
+fn spawn_measure(conn: Db) {
+    tokio::spawn(async move {
+        let names = match list_unmeasured_files(&*conn.lock().await) {
+            Ok(names) => names,
+            Err(e) => {
+                warn!("failed to list files to measure\n{e:#?}");
+                return;
+            }
+        };
+        for name in names {
+            let data = match get_file(&*conn.lock().await, &name) {
+                Ok(file) => file.data,
+                Err(e) => {
+                    warn!("{name}: failed to get file to measure\n{e:#?}");
+                    continue;
+                }
+            };
+            // decoding all of it takes a while, don't hold up the db in the meantime
+            match tokio::task::spawn_blocking(move || measure(data)).await {
+                Ok(Ok(Some(loudness))) => {
+                    if let Err(e) = set_file_loudness(&*conn.lock().await, &name, loudness) {
+                        warn!("{name}: failed to save loudness\n{e:#?}");
+                    }
+                }
+                Ok(Ok(None)) => debug!("{name}: silent, not normalizing it"),
+                Ok(Err(e)) => warn!("{name}: failed to measure loudness\n{e:#?}"),
+                Err(e) => warn!("{name}: failed to measure loudness\n{e:#?}"),
+            }
+        }
+    });
+}
+/// returns false if the file doesn't exist
+pub fn set_file_gain(conn: &Connection, name: &str, gain: f32) -> Result<bool> {
+    Ok(conn.execute(
+        "UPDATE files SET gain = ?1 WHERE name == ?2",
+        params![gain, name],
+    )? == 1)
+}
+/// the length of the decoded audio, decoding and saving it the first time
+pub fn get_file_duration(conn: &Connection, name: &str) -> anyhow::Result<Duration> {
+    let secs: Option<f64> = conn.query_row(
+        "SELECT duration FROM files WHERE name == ?",
+        (name,),
+        |r| r.get(0),
+    )?;
+    if let Some(secs) = secs {
+        return Ok(Duration::from_secs_f64(secs));
+    }
 
-pub fn insert_task(conn: &Connection, task: &Task) -> Result<()> {
+    let len = track_length(get_file(conn, name)?.data)?;
     conn.execute(
-        "INSERT INTO tasks (type, name, priority, file_name, time) VALUES (?1, ?2, ?3, ?4, ?5)",
+        "UPDATE files SET duration = ?1 WHERE name == ?2",
+        params![len.as_secs_f64(), name],
+    )?;
+    Ok(len)
+}
+/// the length of a file, or of a whole playlist with its gaps
+pub fn get_duration(conn: &Connection, name: &str) -> anyhow::Result<Duration> {
+    match get_playlist(conn, name)? {
+        Some(playlist) => playlist.items.iter().try_fold(Duration::ZERO, |len, item| {
+            Ok(len + get_file_duration(conn, &item.file_name)? + Duration::from_secs_f64(item.gap))
+        }),
+        None => get_file_duration(conn, name),
+    }
+}
+/// fails if a playlist still plays it
+pub fn delete_file(conn: &Connection, name: &str) -> anyhow::Result<()> {
+    let used: i64 = conn.query_row(
+        "SELECT COUNT(DISTINCT playlist) FROM playlist_items WHERE file_name == ?",
+        (name,),
+        |r| r.get(0),
+    )?;
+    if used > 0 {
+        anyhow::bail!("File is used by {used} playlist(s)");
+    }
+    conn.execute("DELETE FROM files WHERE name == ?", (name,))?;
+    Ok(())
+}
+
+/// playlists share their names with files, so tasks can refer to either
+pub fn insert_playlist(conn: &Connection, name: &str) -> anyhow::Result<()> {
+    if list_files(conn)?.iter().any(|f| f == name) {
+        anyhow::bail!("Name already in use");
+    }
+    conn.execute("INSERT INTO playlists (name) VALUES (?)", (name,))
+        .map_err(db_err)?;
+    Ok(())
+}
+pub fn list_playlists(conn: &Connection) -> Result<Vec<Playlist>> {
+    let mut s = conn.prepare("SELECT name FROM playlists ORDER BY name")?;
+    let names = s.query_map([], |r| r.get::<_, String>(0))?;
+    names
+        .map(|name| {
+            let name = name?;
+            let items = list_playlist_items(conn, &name)?;
+            Ok(Playlist { name, items })
+        })
+        .collect()
+}
+/// `None` if there is no playlist called `name`, eg. because it's a file
+pub fn get_playlist(conn: &Connection, name: &str) -> Result<Option<Playlist>> {
+    match conn.query_row("SELECT name FROM playlists WHERE name == ?", (name,), |r| {
+        r.get::<_, String>(0)
+    }) {
+        Ok(name) => Ok(Some(Playlist {
+            items: list_playlist_items(conn, &name)?,
+            name,
+        })),
+        Err(Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+fn list_playlist_items(conn: &Connection, playlist: &str) -> Result<Vec<PlaylistItem>> {
+    let mut s = conn
+        .prepare("SELECT id, file_name, gap FROM playlist_items WHERE playlist == ? ORDER BY id")?;
+    let res = s.query_map((playlist,), |r| {
+        Ok(PlaylistItem {
+            id: r.get(0)?,
+            file_name: r.get(1)?,
+            gap: r.get(2)?,
+        })
+    })?;
+    res.collect()
+}
+/// fails if a task still uses the playlist
+pub fn delete_playlist(conn: &Connection, name: &str) -> anyhow::Result<bool> {
+    let used: i64 = conn.query_row(
+        "SELECT (SELECT COUNT(*) FROM tasks WHERE file_name == ?1)
+              + (SELECT COUNT(*) FROM exceptions WHERE file_name == ?1)",
+        (name,),
+        |r| r.get(0),
+    )?;
+    if used > 0 {
+        anyhow::bail!("Playlist is used by {used} task(s)");
+    }
+
+    let tr = conn.unchecked_transaction()?;
+    tr.execute("DELETE FROM playlist_items WHERE playlist == ?", (name,))?;
+    let deleted = tr.execute("DELETE FROM playlists WHERE name == ?", (name,))? == 1;
+    tr.commit()?;
+    Ok(deleted)
+}
+/// add a file to the end of a playlist
+pub fn insert_playlist_item(
+    conn: &Connection,
+    playlist: &str,
+    file_name: &str,
+    gap: f64,
+) -> anyhow::Result<()> {
+    if get_playlist(conn, playlist)?.is_none() {
+        anyhow::bail!("Playlist not found");
+    }
+    // no playlists in playlists
+    if !list_files(conn)?.iter().any(|f| f == file_name) {
+        anyhow::bail!("File not found");
+    }
+    conn.execute(
+        "INSERT INTO playlist_items (playlist, file_name, gap) VALUES (?1, ?2, ?3)",
+        params![playlist, file_name, gap],
+    )?;
+    Ok(())
+}
+pub fn delete_playlist_item(conn: &Connection, playlist: &str, id: i64) -> Result<bool> {
+    Ok(conn.execute(
+        "DELETE FROM playlist_items WHERE id == ?1 AND playlist == ?2",
+        params![id, playlist],
+    )? == 1)
+}
+
+/// `now` is when it last ran, so it won't catch up on plays before it
+pub fn insert_task(conn: &Connection, task: &Task, now: DateTime<Local>) -> Result<()> {
+    conn.execute(
+        "INSERT INTO tasks (type, name, zones, file_name, time, days, profile, missed, grace, last_run,
+                            valid_from, valid_until, anchor, anchor_offset, gain, fade_in, fade_out, urgency)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18)",
         params![
             task.get_type(),
             task.get_name(),
-            task.get_priority(),
+            task.zones_to_str(),
             task.get_file_name(),
-            task.time_to_str()
+            task.time_to_str(),
+            task.days_to_str(),
+            task.get_profile(),
+            task.get_missed().unwrap_or_default().as_str(),
+            grace(task),
+            now,
+            task.get_valid_from(),
+            task.get_valid_until(),
+            task.get_anchor().map(|a| &a.task),
+            task.get_anchor().map(|a| a.offset),
+            task.get_gain(),
+            task.get_fade().fade_in,
+            task.get_fade().fade_out,
+            task.get_urgency().as_str()
         ],
     )
     .map(|_| ())
 }
+/// replace the task called `name`, returns false if it doesn't exist
+pub fn update_task(
+    conn: &Connection,
+    name: &str,
+    task: &Task,
+    now: DateTime<Local>,
+) -> Result<bool> {
+    // the exceptions follow the task, or neither changes
+    let tr = conn.unchecked_transaction()?;
+    if name != task.get_name() {
+        tr.execute(
+            "UPDATE exceptions SET task = ?1 WHERE task == ?2",
+            (task.get_name(), name),
+        )?;
+    }
+    let updated = tr.execute(
+        "UPDATE tasks SET type = ?1, name = ?2, zones = ?3, file_name = ?4, time = ?5, days = ?6, profile = ?7,
+                          missed = ?8, grace = ?9, last_run = ?10, valid_from = ?11, valid_until = ?12,
+                          anchor = ?13, anchor_offset = ?14, gain = ?15, fade_in = ?16, fade_out = ?17,
+                          urgency = ?18
+         WHERE name == ?19",
+        params![
+            task.get_type(),
+            task.get_name(),
+            task.zones_to_str(),
+            task.get_file_name(),
+            task.time_to_str(),
+            task.days_to_str(),
+            task.get_profile(),
+            task.get_missed().unwrap_or_default().as_str(),
+            grace(task),
+            now,
+            task.get_valid_from(),
+            task.get_valid_until(),
+            task.get_anchor().map(|a| &a.task),
+            task.get_anchor().map(|a| a.offset),
+            task.get_gain(),
+            task.get_fade().fade_in,
+            task.get_fade().fade_out,
+            task.get_urgency().as_str(),
+            name
+        ],
+    )? == 1;
+    if updated {
+        tr.commit()?;
+    }
+    Ok(updated)
+}
+fn grace(task: &Task) -> Option<u32> {
+    match task.get_missed() {
+        Some(Missed::Play { grace }) => Some(grace),
+        _ => None,
+    }
+}
+/// the time of the last play the task handled, `None` if it never ran
+pub fn get_last_run(conn: &Connection, name: &str) -> Result<Option<DateTime<Local>>> {
+    conn.query_row(
+        "SELECT last_run FROM tasks WHERE name == ?",
+        (name,),
+        |r| r.get(0),
+    )
+}
+pub fn set_last_run(conn: &Connection, name: &str, time: DateTime<Local>) -> Result<()> {
+    conn.execute(
+        "UPDATE tasks SET last_run = ?1 WHERE name == ?2",
+        params![time, name],
+    )
+    .map(|_| ())
+}
+/// the tasks that aren't archived
 pub fn list_tasks(conn: &Connection) -> Result<Vec<Task>> {
-    let mut s = conn.prepare("SELECT * FROM tasks")?;
+    let mut s = conn.prepare("SELECT * FROM tasks WHERE archived == 0")?;
     let res = s.query_map([], parse_task)?;
     res.collect()
 }
+pub fn list_archived_tasks(conn: &Connection) -> Result<Vec<Task>> {
+    let mut s = conn.prepare("SELECT * FROM tasks WHERE archived == 1 ORDER BY valid_until")?;
+    let res = s.query_map([], parse_task)?;
+    res.collect()
+}
+/// keep an expired task around, without scheduling it anymore
+pub fn archive_task(conn: &Connection, name: &str) -> Result<bool> {
+    conn.execute("DELETE FROM exceptions WHERE task == ?", (name,))?;
+    Ok(conn.execute("UPDATE tasks SET archived = 1 WHERE name == ?", (name,))? == 1)
+}
 pub fn get_task(conn: &Connection, name: &str) -> Result<Task> {
     conn.query_row("SELECT * FROM tasks WHERE name == ?", (name,), parse_task)
 }
 pub fn delete_task(conn: &Connection, name: &str) -> Result<bool> {
+    conn.execute("DELETE FROM exceptions WHERE task == ?", (name,))?;
+    // the tasks following it keep their current time
+    conn.execute(
+        "UPDATE tasks SET anchor = NULL, anchor_offset = NULL WHERE anchor == ?",
+        (name,),
+    )?;
     Ok(conn.execute("DELETE FROM tasks WHERE name == ?", (name,))? == 1)
 }
 
+pub fn insert_exception(conn: &Connection, e: &Exception) -> Result<()> {
+    let (new_time, file_name) = match &e.action {
+        Action::Skip => (None, None),
+        Action::Move { to } => (Some(to.format(TIMEFMT).to_string()), None),
+        Action::Replace { file_name } => (None, Some(file_name)),
+    };
+    conn.execute(
+        "INSERT INTO exceptions (task, date, time, action, new_time, file_name) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![
+            e.task,
+            e.date,
+            e.time.format(TIMEFMT).to_string(),
+            e.action.get_type(),
+            new_time,
+            file_name
+        ],
+    )
+    .map(|_| ())
+}
+/// all exceptions, or only the ones of `task`
+pub fn list_exceptions(conn: &Connection, task: Option<&str>) -> Result<Vec<Exception>> {
+    let mut s = conn.prepare(
+        "SELECT * FROM exceptions WHERE ?1 IS NULL OR task == ?1 ORDER BY date, time",
+    )?;
+    let res = s.query_map((task,), parse_exception)?;
+    res.collect()
+}
+/// returns the name of the task the exception belonged to
+pub fn delete_exception(conn: &Connection, id: i64) -> Result<Option<String>> {
+    match conn.query_row(
+        "DELETE FROM exceptions WHERE id == ? RETURNING task",
+        (id,),
+        |r| r.get(0),
+    ) {
+        Ok(task) => Ok(Some(task)),
+        Err(Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn parse_exception(r: &Row) -> Result<Exception, Error> {
+    let parse_time = |col: &str| {
+        NaiveTime::parse_from_str(&r.get::<_, String>(col)?, TIMEFMT).map_err(|e| {
+            rusqlite::Error::FromSqlConversionFailure(
+                r.as_ref().column_index(col).unwrap_or_default(),
+                rusqlite::types::Type::Text,
+                Box::new(e),
+            )
+        })
+    };
+    Ok(Exception {
+        id: r.get("id")?,
+        task: r.get("task")?,
+        date: r.get("date")?,
+        time: parse_time("time")?,
+        action: match r.get::<_, String>("action")?.as_str() {
+            "skip" => Action::Skip,
+            "move" => Action::Move {
+                to: parse_time("new_time")?,
+            },
+            "replace" => Action::Replace {
+                file_name: r.get("file_name")?,
+            },
+            _ => unreachable!(),
+        },
+    })
+}
+
 fn parse_task(r: &Row) -> Result<Task, Error> {
-    Ok(match r.get::<_, String>(0)?.as_str() {
+    Ok(match r.get::<_, String>("type")?.as_str() {
         "now" => Task::Now {
-            name: r.get(1)?,
-            priority: r.get(2)?,
-            file_name: r.get(3)?,
+            name: r.get("name")?,
+            zones: parse_list(r, "zones", str::parse::<String>)?,
+            file_name: r.get("file_name")?,
+            gain: r.get("gain")?,
+            fade: parse_fade(r)?,
+            urgency: parse_urgency(r)?,
         },
         "scheduled" => Task::Scheduled {
-            name: r.get(1)?,
-            priority: r.get(2)?,
-            file_name: r.get(3)?,
-            time: r.get(4)?,
+            name: r.get("name")?,
+            zones: parse_list(r, "zones", str::parse::<String>)?,
+            file_name: r.get("file_name")?,
+            time: r.get("time")?,
+            missed: parse_missed(r)?,
+            anchor: match r.get::<_, Option<String>>("anchor")? {
+                Some(task) => Some(Anchor {
+                    task,
+                    offset: r.get("anchor_offset")?,
+                }),
+                None => None,
+            },
+            gain: r.get("gain")?,
+            fade: parse_fade(r)?,
+            urgency: parse_urgency(r)?,
         },
         "recurring" => Task::Recurring {
-            name: r.get(1)?,
-            priority: r.get(2)?,
-            file_name: r.get(3)?,
-            time: r
-                .get::<_, String>(4)?
-                .split(';')
-                .map(|s| {
-                    NaiveTime::parse_from_str(s, TIMEFMT).map_err(|e| {
-                        rusqlite::Error::FromSqlConversionFailure(
-                            3,
-                            rusqlite::types::Type::Text,
-                            Box::new(e),
-                        )
-                    })
-                })
-                .collect::<Result<Vec<NaiveTime>>>()?,
+            name: r.get("name")?,
+            zones: parse_list(r, "zones", str::parse::<String>)?,
+            file_name: r.get("file_name")?,
+            time: parse_list(r, "time", |s| NaiveTime::parse_from_str(s, TIMEFMT))?,
+            days: parse_list(r, "days", str::parse::<Weekday>)?,
+            profile: r.get("profile")?,
+            missed: parse_missed(r)?,
+            valid_from: r.get("valid_from")?,
+            valid_until: r.get("valid_until")?,
+            gain: r.get("gain")?,
+            fade: parse_fade(r)?,
+            urgency: parse_urgency(r)?,
+        },
+        "cron" => Task::Cron {
+            name: r.get("name")?,
+            zones: parse_list(r, "zones", str::parse::<String>)?,
+            file_name: r.get("file_name")?,
+            expr: r.get("time")?,
+            missed: parse_missed(r)?,
+            gain: r.get("gain")?,
+            fade: parse_fade(r)?,
+            urgency: parse_urgency(r)?,
         },
         _ => unreachable!(),
     })
 }
 
+fn parse_fade(r: &Row) -> Result<Fade, Error> {
+    Ok(Fade {
+        fade_in: r.get("fade_in")?,
+        fade_out: r.get("fade_out")?,
+    })
+}
+
+fn parse_urgency(r: &Row) -> Result<Urgency, Error> {
+    r.get::<_, String>("urgency")?
+        .parse()
+        .map_err(|e: anyhow::Error| {
+            rusqlite::Error::FromSqlConversionFailure(
+                r.as_ref().column_index("urgency").unwrap_or_default(),
+                rusqlite::types::Type::Text,
+                e.into(),
+            )
+        })
+}
+
+fn parse_missed(r: &Row) -> Result<Missed, Error> {
+    Ok(match r.get::<_, String>("missed")?.as_str() {
+        "play" => Missed::Play {
+            grace: r
+                .get::<_, Option<u32>>("grace")?
+                .unwrap_or(Missed::DEFAULT_GRACE),
+        },
+        "notify" => Missed::Notify,
+        _ => Missed::Drop,
+    })
+}
+
+/// parse a `;` separated column
+fn parse_list<T, E: std::error::Error + Send + Sync + 'static>(
+    r: &Row,
+    col: &str,
+    f: impl Fn(&str) -> std::result::Result<T, E>,
+) -> Result<Vec<T>> {
+    let s = r.get::<_, String>(col)?;
+    if s.is_empty() {
+        return Ok(Vec::new());
+    }
+    s.split(';')
+        .map(|s| {
+            f(s).map_err(|e| {
+                rusqlite::Error::FromSqlConversionFailure(
+                    r.as_ref().column_index(col).unwrap_or_default(),
+                    rusqlite::types::Type::Text,
+                    Box::new(e),
+                )
+            })
+        })
+        .collect()
+}
+
+pub fn insert_calendar_entry(conn: &Connection, entry: &Entry) -> Result<()> {
+    conn.execute(
+        "INSERT INTO calendar (kind, name, start, end) VALUES (?1, ?2, ?3, ?4)",
+        params![entry.kind.as_str(), entry.name, entry.start, entry.end],
+    )
+    .map(|_| ())
+}
+pub fn list_calendar(conn: &Connection) -> Result<Vec<Entry>> {
+    let mut s = conn.prepare("SELECT * FROM calendar ORDER BY start")?;
+    let res = s.query_map([], parse_calendar_entry)?;
+    res.collect()
+}
+pub fn delete_calendar_entry(conn: &Connection, id: i64) -> Result<bool> {
+    Ok(conn.execute("DELETE FROM calendar WHERE id == ?", (id,))? == 1)
+}
+/// the calendar entry covering `date`, if any
+pub fn get_calendar_entry(conn: &Connection, date: NaiveDate) -> Result<Option<Entry>> {
+    match conn.query_row(
+        "SELECT * FROM calendar WHERE start <= ?1 AND end >= ?1",
+        (date,),
+        parse_calendar_entry,
+    ) {
+        Ok(e) => Ok(Some(e)),
+        Err(Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn parse_calendar_entry(r: &Row) -> Result<Entry, Error> {
+    Ok(Entry {
+        id: r.get("id")?,
+        kind: r.get::<_, String>("kind")?.parse().map_err(|e| {
+            rusqlite::Error::FromSqlConversionFailure(
+                1,
+                rusqlite::types::Type::Text,
+                Box::new(e),
+            )
+        })?,
+        name: r.get("name")?,
+        start: r.get("start")?,
+        end: r.get("end")?,
+    })
+}
+
+pub fn get_setting(conn: &Connection, key: &str) -> Result<Option<String>> {
+    match conn.query_row("SELECT value FROM settings WHERE key == ?", (key,), |r| {
+        r.get(0)
+    }) {
+        Ok(v) => Ok(Some(v)),
+        Err(Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+/// `None` removes the setting
+pub fn set_setting(conn: &Connection, key: &str, value: Option<&str>) -> Result<()> {
+    match value {
+        Some(v) => conn.execute(
+            "INSERT INTO settings (key, value) VALUES (?1, ?2)
+             ON CONFLICT (key) DO UPDATE SET value = excluded.value",
+            (key, v),
+        ),
+        None => conn.execute("DELETE FROM settings WHERE key == ?", (key,)),
+    }
+    .map(|_| ())
+}
+
+/// the end of the pause of all schedules, even if it has already passed
+pub fn get_paused_until(conn: &Connection) -> Result<Option<DateTime<Local>>> {
+    get_setting(conn, PAUSED_UNTIL)?
+        .map(|v| match DateTime::parse_from_str(&v, PAUSED_UNTIL_FMT) {
+            Ok(t) => Ok(t.with_timezone(&Local)),
+            Err(e) => Err(Error::FromSqlConversionFailure(0, Type::Text, Box::new(e))),
+        })
+        .transpose()
+}
+/// `None` ends the pause
+pub fn set_paused_until(conn: &Connection, until: Option<DateTime<Local>>) -> Result<()> {
+    let value = until.map(|t| t.format(PAUSED_UNTIL_FMT).to_string());
+    set_setting(conn, PAUSED_UNTIL, value.as_deref())
+}
+
+/// `None` if normalization is turned off
+pub fn get_target_loudness(conn: &Connection) -> anyhow::Result<Option<f64>> {
+    match get_setting(conn, TARGET_LOUDNESS)?.as_deref() {
+        Some("off") => Ok(None),
+        Some(v) => Ok(Some(v.parse()?)),
+        None => Ok(Some(DEFAULT_TARGET_LOUDNESS)),
+    }
+}
+pub fn set_target_loudness(conn: &Connection, target: Option<f64>) -> anyhow::Result<()> {
+    if target.is_some_and(|t| !(-40.0..=-5.0).contains(&t)) {
+        anyhow::bail!("Target loudness must be between -40 and -5 LUFS");
+    }
+    let value = target.map_or_else(|| "off".to_owned(), |t| t.to_string());
+    Ok(set_setting(conn, TARGET_LOUDNESS, Some(&value))?)
+}
+
+/// the master volume, full by default
+pub fn get_volume(conn: &Connection) -> anyhow::Result<f32> {
+    match get_setting(conn, VOLUME)? {
+        Some(v) => Ok(v.parse()?),
+        None => Ok(1.0),
+    }
+}
+pub fn set_volume(conn: &Connection, volume: f32) -> Result<()> {
+    set_setting(conn, VOLUME, Some(&volume.to_string()))
+}
+
+/// seconds, how long a track fades out when it's stopped
+pub fn get_stop_fade(conn: &Connection) -> anyhow::Result<f32> {
+    match get_setting(conn, STOP_FADE)? {
+        Some(v) => Ok(v.parse()?),
+        None => Ok(DEFAULT_STOP_FADE),
+    }
+}
+pub fn set_stop_fade(conn: &Connection, secs: f32) -> Result<()> {
+    set_setting(conn, STOP_FADE, Some(&secs.to_string()))
+}
+
+/// the device chosen for an output, `Some(None)` for the default one, `None` if it wasn't changed
+pub fn get_output_device(conn: &Connection, output: &str) -> Result<Option<Option<String>>> {
+    Ok(get_setting(conn, &format!("{OUTPUT_DEVICE}{output}"))?
+        .map(|d| (!d.is_empty()).then_some(d)))
+}
+pub fn set_output_device(conn: &Connection, output: &str, device: Option<&str>) -> Result<()> {
+    set_setting(
+        conn,
+        &format!("{OUTPUT_DEVICE}{output}"),
+        Some(device.unwrap_or_default()),
+    )
+}
+
+pub fn insert_profile(conn: &Connection, name: &str) -> Result<()> {
+    conn.execute("INSERT INTO profiles (name) VALUES (?)", (name,))
+        .map(|_| ())
+}
+pub fn list_profiles(conn: &Connection) -> Result<Vec<String>> {
+    let mut s = conn.prepare("SELECT name FROM profiles ORDER BY name")?;
+    let res = s.query_map([], |r| r.get(0))?;
+    res.collect()
+}
+/// fails if a task still uses the profile
+pub fn delete_profile(conn: &Connection, name: &str) -> anyhow::Result<bool> {
+    let used: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM tasks WHERE profile == ?",
+        (name,),
+        |r| r.get(0),
+    )?;
+    if used > 0 {
+        anyhow::bail!("Profile is used by {used} task(s)");
+    }
+
+    let tr = conn.unchecked_transaction()?;
+    tr.execute("DELETE FROM profile_overrides WHERE profile == ?", (name,))?;
+    if get_setting(&tr, ACTIVE_PROFILE)?.as_deref() == Some(name) {
+        set_setting(&tr, ACTIVE_PROFILE, None)?;
+    }
+    let deleted = tr.execute("DELETE FROM profiles WHERE name == ?", (name,))? == 1;
+    tr.commit()?;
+    Ok(deleted)
+}
+pub fn get_active_profile(conn: &Connection) -> Result<Option<String>> {
+    get_setting(conn, ACTIVE_PROFILE)
+}
+pub fn set_active_profile(conn: &Connection, name: Option<&str>) -> anyhow::Result<()> {
+    if let Some(name) = name {
+        if !list_profiles(conn)?.iter().any(|p| p == name) {
+            anyhow::bail!("Profile not found");
+        }
+    }
+    Ok(set_setting(conn, ACTIVE_PROFILE, name)?)
+}
+
+pub fn insert_profile_override(conn: &Connection, date: NaiveDate, profile: &str) -> Result<()> {
+    conn.execute(
+        "INSERT INTO profile_overrides (date, profile) VALUES (?1, ?2)
+         ON CONFLICT (date) DO UPDATE SET profile = excluded.profile",
+        params![date, profile],
+    )
+    .map(|_| ())
+}
+pub fn list_profile_overrides(conn: &Connection) -> Result<Vec<(NaiveDate, String)>> {
+    let mut s = conn.prepare("SELECT date, profile FROM profile_overrides ORDER BY date")?;
+    let res = s.query_map([], |r| Ok((r.get(0)?, r.get(1)?)))?;
+    res.collect()
+}
+pub fn delete_profile_override(conn: &Connection, date: NaiveDate) -> Result<bool> {
+    Ok(conn.execute("DELETE FROM profile_overrides WHERE date == ?", (date,))? == 1)
+}
+/// the profile in effect on `date`: the override for that day, or the active one
+pub fn get_profile_for(conn: &Connection, date: NaiveDate) -> Result<Option<String>> {
+    match conn.query_row(
+        "SELECT profile FROM profile_overrides WHERE date == ?",
+        (date,),
+        |r| r.get(0),
+    ) {
+        Ok(p) => Ok(Some(p)),
+        Err(Error::QueryReturnedNoRows) => get_active_profile(conn),
+        Err(e) => Err(e),
+    }
+}
+
 pub fn db_err(e: rusqlite::Error) -> anyhow::Error {
     match e {
         rusqlite::Error::SqliteFailure(
@@ -41,17 +41,160 @@ Időpont: {}
         time.naive_local()
     );
 
+    send(
+        addr,
+        &pass,
+        format!("Adás: {}", file_name),
+        &html_body,
+        &text_body,
+    )
+    .await;
+}
+
+/// a play was missed, eg. because the computer was off
+pub async fn task_missed(file_name: String, time: DateTime<Local>) {
+    let (addr, pass, signature) = match get_vars() {
+        Some(x) => x,
+        None => return,
+    };
+
+    let html_body = format!(
+        r#"
+<p>Tisztelt Tanár úr!</p>
+
+<p>
+A következő adás elmaradt: <br/>
+Név: <b>{}</b> <br/>
+Időpont: <b>{}</b>
+</p>
+
+ <p>Üdvözlettel,<br/>{signature}</p>"#,
+        file_name,
+        time.naive_local()
+    );
+
+    let text_body = format!(
+        r#"
+Tisztelt Tanár úr!
+
+A következő adás elmaradt:
+Név: {}
+Időpont: {}
+
+Üdvözlettel,
+{signature}"#,
+        file_name,
+        time.naive_local()
+    );
+
+    send(
+        &addr,
+        &pass,
+        format!("Elmaradt adás: {}", file_name),
+        &html_body,
+        &text_body,
+    )
+    .await;
+}
+
+/// an output device stopped working, nothing can be played on it until it's back
+pub async fn output_lost(output: String, error: String) {
+    let (addr, pass, signature) = match get_vars() {
+        Some(x) => x,
+        None => return,
+    };
+
+    let html_body = format!(
+        r#"
+<p>Tisztelt Tanár úr!</p>
+
+<p>
+A következő hangkimenet nem működik, amíg vissza nem áll, nem szólnak rajta a csengetések: <br/>
+Kimenet: <b>{}</b> <br/>
+Hiba: <b>{}</b>
+</p>
+
+ <p>Üdvözlettel,<br/>{signature}</p>"#,
+        output, error
+    );
+
+    let text_body = format!(
+        r#"
+Tisztelt Tanár úr!
+
+A következő hangkimenet nem működik, amíg vissza nem áll, nem szólnak rajta a csengetések:
+Kimenet: {}
+Hiba: {}
+
+Üdvözlettel,
+{signature}"#,
+        output, error
+    );
+
+    send(
+        &addr,
+        &pass,
+        format!("Nem működő hangkimenet: {}", output),
+        &html_body,
+        &text_body,
+    )
+    .await;
+}
+
+/// a lost output device works again
+pub async fn output_restored(output: String) {
+    let (addr, pass, signature) = match get_vars() {
+        Some(x) => x,
+        None => return,
+    };
+
+    let html_body = format!(
+        r#"
+<p>Tisztelt Tanár úr!</p>
+
+<p>
+A következő hangkimenet újra működik: <br/>
+Kimenet: <b>{}</b>
+</p>
+
+ <p>Üdvözlettel,<br/>{signature}</p>"#,
+        output
+    );
+
+    let text_body = format!(
+        r#"
+Tisztelt Tanár úr!
+
+A következő hangkimenet újra működik:
+Kimenet: {}
+
+Üdvözlettel,
+{signature}"#,
+        output
+    );
+
+    send(
+        &addr,
+        &pass,
+        format!("Helyreállt hangkimenet: {}", output),
+        &html_body,
+        &text_body,
+    )
+    .await;
+}
+
+async fn send(addr: &str, pass: &str, subject: String, html_body: &str, text_body: &str) {
     let message = MessageBuilder::new()
         .from(("Csengő Mail", addr))
         .to(addr)
         .reply_to(addr)
-        .subject(format!("Adás: {}", file_name))
+        .subject(subject)
         .html_body(html_body.trim())
         .text_body(text_body.trim());
 
     let mut transport = match SmtpClientBuilder::new("smtp.gmail.com", 465)
         .implicit_tls(true)
-        .credentials((addr, pass.as_str()))
+        .credentials((addr, pass))
         .connect()
         .await
     {
@@ -1,3 +1,6 @@
+mod backend;
+mod calendar;
+mod clock;
 mod db;
 mod mail;
 mod metrics;
@@ -6,11 +9,12 @@ mod scheduler;
 mod server;
 mod sink;
 mod templates;
+mod zones;
 
 #[macro_use]
 extern crate log;
 use bytes::Bytes;
-use chrono::{DateTime, Local, NaiveTime, SecondsFormat};
+use chrono::{DateTime, Local, NaiveDate, NaiveTime, SecondsFormat, Weekday};
 use serde::{Deserialize, Serialize};
 use std::env;
 
@@ -37,8 +41,25 @@ async fn main() -> anyhow::Result<()> {
     db::update_file_stats(&*conn.lock().await);
 
     // audio setup
-    let (controller, np_rx) = sink::Controller::init();
-    let player = player::Player::new(controller, np_rx, conn, metrics_handle);
+    let mut controllers = Vec::new();
+    {
+        let conn = conn.lock().await;
+        let volume = db::get_volume(&conn)?;
+        let stop_fade = db::get_stop_fade(&conn)?;
+        for output in zones::outputs() {
+            // a device picked in the ui overrides the config
+            let device = match db::get_output_device(&conn, &output.name)? {
+                Some(device) => device,
+                None => output.device.clone(),
+            };
+            let controller = sink::Controller::init(output.name.clone(), device)?;
+            controller.set_volume(volume);
+            controller.set_stop_fade(stop_fade);
+            controllers.push(controller);
+        }
+    }
+    let clock = std::sync::Arc::new(clock::SystemClock);
+    let player = player::Player::new(controllers, conn, metrics_handle, clock);
 
     if !db_new {
         let l = db::load(player.clone()).await?;
@@ -53,26 +74,82 @@ async fn main() -> anyhow::Result<()> {
 }
 
 // === data structures ===
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 #[serde(tag = "type")]
 #[serde(rename_all = "camelCase")]
 pub enum Task {
     Now {
         name: String,
-        priority: bool,
+        /// the zones to play in, the first one if empty
+        #[serde(default)]
+        zones: Vec<String>,
         file_name: String,
+        /// dB, added to the gain of the file
+        #[serde(default)]
+        gain: f32,
+        #[serde(default)]
+        fade: Fade,
+        #[serde(default)]
+        urgency: Urgency,
     },
     Scheduled {
         name: String,
         file_name: String,
-        priority: bool,
+        #[serde(default)]
+        zones: Vec<String>,
         time: DateTime<Local>,
+        #[serde(default)]
+        missed: Missed,
+        /// the time follows the next play of another task
+        #[serde(default)]
+        anchor: Option<Anchor>,
+        #[serde(default)]
+        gain: f32,
+        #[serde(default)]
+        fade: Fade,
+        #[serde(default)]
+        urgency: Urgency,
     },
     Recurring {
         name: String,
         file_name: String,
-        priority: bool,
+        #[serde(default)]
+        zones: Vec<String>,
         time: Vec<NaiveTime>,
+        #[serde(default = "all_days")]
+        days: Vec<Weekday>,
+        /// only ring when this bell schedule profile is in effect
+        #[serde(default)]
+        profile: Option<String>,
+        #[serde(default)]
+        missed: Missed,
+        /// the first day it rings on
+        #[serde(default)]
+        valid_from: Option<NaiveDate>,
+        /// the last day it rings on
+        #[serde(default)]
+        valid_until: Option<NaiveDate>,
+        #[serde(default)]
+        gain: f32,
+        #[serde(default)]
+        fade: Fade,
+        #[serde(default)]
+        urgency: Urgency,
+    },
+    Cron {
+        name: String,
+        file_name: String,
+        #[serde(default)]
+        zones: Vec<String>,
+        expr: String,
+        #[serde(default)]
+        missed: Missed,
+        #[serde(default)]
+        gain: f32,
+        #[serde(default)]
+        fade: Fade,
+        #[serde(default)]
+        urgency: Urgency,
     },
 }
 impl Task {
@@ -81,13 +158,15 @@ impl Task {
             Task::Now { .. } => true,
             Task::Scheduled { .. } => false,
             Task::Recurring { .. } => false,
+            Task::Cron { .. } => false,
         }
     }
-    pub fn get_type(&self) -> &str {
+    pub fn get_type(&self) -> &'static str {
         match self {
             Task::Now { .. } => "now",
             Task::Scheduled { .. } => "scheduled",
             Task::Recurring { .. } => "recurring",
+            Task::Cron { .. } => "cron",
         }
     }
     pub fn get_name(&self) -> &String {
@@ -95,13 +174,76 @@ impl Task {
             Task::Now { name, .. } => name,
             Task::Scheduled { name, .. } => name,
             Task::Recurring { name, .. } => name,
+            Task::Cron { name, .. } => name,
+        }
+    }
+    pub fn get_zones(&self) -> &[String] {
+        match self {
+            Task::Now { zones, .. } => zones,
+            Task::Scheduled { zones, .. } => zones,
+            Task::Recurring { zones, .. } => zones,
+            Task::Cron { zones, .. } => zones,
+        }
+    }
+    pub fn get_gain(&self) -> f32 {
+        match *self {
+            Task::Now { gain, .. } => gain,
+            Task::Scheduled { gain, .. } => gain,
+            Task::Recurring { gain, .. } => gain,
+            Task::Cron { gain, .. } => gain,
         }
     }
-    pub fn get_priority(&self) -> bool {
+    pub fn get_fade(&self) -> Fade {
         match *self {
-            Task::Now { priority, .. } => priority,
-            Task::Scheduled { priority, .. } => priority,
-            Task::Recurring { priority, .. } => priority,
+            Task::Now { fade, .. } => fade,
+            Task::Scheduled { fade, .. } => fade,
+            Task::Recurring { fade, .. } => fade,
+            Task::Cron { fade, .. } => fade,
+        }
+    }
+    pub fn get_urgency(&self) -> Urgency {
+        match *self {
+            Task::Now { urgency, .. } => urgency,
+            Task::Scheduled { urgency, .. } => urgency,
+            Task::Recurring { urgency, .. } => urgency,
+            Task::Cron { urgency, .. } => urgency,
+        }
+    }
+    pub fn get_profile(&self) -> Option<&str> {
+        match self {
+            Task::Recurring { profile, .. } => profile.as_deref(),
+            _ => None,
+        }
+    }
+    pub fn get_anchor(&self) -> Option<&Anchor> {
+        match self {
+            Task::Scheduled { anchor, .. } => anchor.as_ref(),
+            _ => None,
+        }
+    }
+    pub fn get_valid_from(&self) -> Option<NaiveDate> {
+        match *self {
+            Task::Recurring { valid_from, .. } => valid_from,
+            _ => None,
+        }
+    }
+    pub fn get_valid_until(&self) -> Option<NaiveDate> {
+        match *self {
+            Task::Recurring { valid_until, .. } => valid_until,
+            _ => None,
+        }
+    }
+    /// whether `date` is within the days the task rings on
+    pub fn is_valid_on(&self, date: NaiveDate) -> bool {
+        self.get_valid_from().is_none_or(|from| from <= date)
+            && self.get_valid_until().is_none_or(|until| date <= until)
+    }
+    pub fn get_missed(&self) -> Option<Missed> {
+        match *self {
+            Task::Now { .. } => None,
+            Task::Scheduled { missed, .. } => Some(missed),
+            Task::Recurring { missed, .. } => Some(missed),
+            Task::Cron { missed, .. } => Some(missed),
         }
     }
     pub fn get_file_name(&self) -> &String {
@@ -109,6 +251,7 @@ impl Task {
             Task::Now { file_name, .. } => file_name,
             Task::Scheduled { file_name, .. } => file_name,
             Task::Recurring { file_name, .. } => file_name,
+            Task::Cron { file_name, .. } => file_name,
         }
     }
     pub fn time_to_str(&self) -> Option<String> {
@@ -121,12 +264,201 @@ impl Task {
                     .collect::<Vec<String>>()
                     .join(";"),
             ),
+            Task::Cron { expr, .. } => Some(expr.to_owned()),
+        }
+    }
+    pub fn zones_to_str(&self) -> String {
+        self.get_zones().join(";")
+    }
+    pub fn days_to_str(&self) -> Option<String> {
+        match self {
+            Task::Recurring { days, .. } => Some(
+                days.iter()
+                    .map(Weekday::to_string)
+                    .collect::<Vec<String>>()
+                    .join(";"),
+            ),
+            _ => None,
+        }
+    }
+}
+
+/// a time relative to the next play of another task
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Anchor {
+    pub task: String,
+    /// seconds after the play, negative for before it
+    pub offset: i64,
+}
+
+/// what to do with a play that was missed, eg. while csengo wasn't running
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "policy")]
+#[serde(rename_all = "camelCase")]
+pub enum Missed {
+    #[default]
+    Drop,
+    /// play it late, if it's late by at most `grace` minutes
+    Play { grace: u32 },
+    /// don't play it, but send an email about it
+    Notify,
+}
+impl Missed {
+    pub const DEFAULT_GRACE: u32 = 5;
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Missed::Drop => "drop",
+            Missed::Play { .. } => "play",
+            Missed::Notify => "notify",
+        }
+    }
+    pub fn label(&self) -> &'static str {
+        match self {
+            Missed::Drop => "Kimarad",
+            Missed::Play { .. } => "Lejátszás késve",
+            Missed::Notify => "Értesítés",
+        }
+    }
+    pub fn grace(&self) -> u32 {
+        match *self {
+            Missed::Play { grace } => grace,
+            _ => Self::DEFAULT_GRACE,
         }
     }
 }
 
+/// what a play does to the one already playing on the same output
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "mode")]
+#[serde(rename_all = "camelCase")]
+pub enum Urgency {
+    /// waits for the ones queued before it
+    #[default]
+    Normal,
+    /// fades out the one playing, which continues after it if `resume`
+    Interrupt {
+        #[serde(default)]
+        resume: bool,
+    },
+    /// plays over the one playing, which is turned down meanwhile
+    Duck,
+}
+impl Urgency {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Urgency::Normal => "normal",
+            Urgency::Interrupt { resume: false } => "interrupt",
+            Urgency::Interrupt { resume: true } => "resume",
+            Urgency::Duck => "duck",
+        }
+    }
+    pub fn label(&self) -> &'static str {
+        match self {
+            Urgency::Normal => "Sorban",
+            Urgency::Interrupt { resume: false } => "Megszakítja",
+            Urgency::Interrupt { resume: true } => "Megszakítja, majd folytatja",
+            Urgency::Duck => "Lehalkítja",
+        }
+    }
+    pub fn is_urgent(&self) -> bool {
+        *self != Urgency::Normal
+    }
+}
+impl std::str::FromStr for Urgency {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Urgency> {
+        Ok(match s {
+            "normal" => Urgency::Normal,
+            "interrupt" => Urgency::Interrupt { resume: false },
+            "resume" => Urgency::Interrupt { resume: true },
+            "duck" => Urgency::Duck,
+            _ => anyhow::bail!("Invalid value `urgency`"),
+        })
+    }
+}
+
+/// every day of the week, starting on monday
+pub fn all_days() -> Vec<Weekday> {
+    use Weekday::*;
+    vec![Mon, Tue, Wed, Thu, Fri, Sat, Sun]
+}
+
+/// a one-off change to a single occurrence of a recurring task
+#[derive(Debug, Clone, Serialize)]
+pub struct Exception {
+    pub id: i64,
+    pub task: String,
+    pub date: NaiveDate,
+    /// the original time of the occurrence
+    pub time: NaiveTime,
+    pub action: Action,
+}
+#[derive(Debug, Clone, Serialize, PartialEq)]
+#[serde(tag = "type")]
+#[serde(rename_all = "camelCase")]
+pub enum Action {
+    Skip,
+    Move { to: NaiveTime },
+    Replace { file_name: String },
+}
+impl Action {
+    pub fn get_type(&self) -> &str {
+        match self {
+            Action::Skip => "skip",
+            Action::Move { .. } => "move",
+            Action::Replace { .. } => "replace",
+        }
+    }
+}
+
+/// files played one after the other, usable anywhere a file is
+#[derive(Debug, Clone, Serialize)]
+pub struct Playlist {
+    pub name: String,
+    pub items: Vec<PlaylistItem>,
+}
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlaylistItem {
+    pub id: i64,
+    pub file_name: String,
+    /// seconds of silence after the file
+    pub gap: f64,
+}
+
+/// how the files of a task start and end, in seconds, 0 for no fade
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+pub struct Fade {
+    #[serde(default, rename = "in")]
+    pub fade_in: f32,
+    #[serde(default, rename = "out")]
+    pub fade_out: f32,
+}
+
 #[derive(Debug)]
 pub struct File {
     name: String,
     data: Bytes,
+    /// dB
+    gain: f32,
+    loudness: Option<Loudness>,
+}
+/// a file as shown in the file list
+#[derive(Debug, Clone, Serialize)]
+pub struct FileInfo {
+    pub name: String,
+    /// dB, added to the gain of the task playing it
+    pub gain: f32,
+    /// `None` if it hasn't been measured yet, or it's silent
+    pub loudness: Option<Loudness>,
+}
+/// the EBU R128 measurements of a file
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Loudness {
+    /// LUFS
+    pub integrated: f64,
+    /// dBTP
+    pub true_peak: f64,
 }
@@ -9,12 +9,17 @@ pub const PLAYBACK_TOTAL: &str = "csengo_playback_total";
 pub const PLAYBACK_SECONDS: &str = "csengo_playback_seconds_total";
 pub const PLAYBACK_ACTIVE: &str = "csengo_playback_active";
 pub const PLAYBACK_QUEUE_SIZE: &str = "csengo_playback_queue_size";
+pub const PLAYBACK_SUPPRESSED: &str = "csengo_playback_suppressed_total";
 pub const AUDIO_ERRORS: &str = "csengo_audio_device_errors_total";
+pub const OUTPUT_UP: &str = "csengo_output_up";
+pub const PAUSES_OVERRIDDEN: &str = "csengo_pauses_overridden_total";
 
 pub const TASKS_CREATED: &str = "csengo_tasks_created_total";
 pub const TASKS_FAILED: &str = "csengo_tasks_failed_total";
 pub const TASKS_ACTIVE: &str = "csengo_tasks_active";
 pub const TASK_DRIFT: &str = "csengo_task_schedule_drift_seconds";
+pub const TASKS_MISSED: &str = "csengo_tasks_missed_total";
+pub const SCHEDULE_PAUSED: &str = "csengo_schedule_paused";
 
 pub const DB_OPS_TOTAL: &str = "csengo_db_operations_total";
 pub const DB_OPS_DURATION: &str = "csengo_db_operation_duration_seconds";
@@ -26,10 +31,10 @@ pub const EMAIL_SENT: &str = "csengo_email_sent_total";
 pub const HTTP_REQUESTS: &str = "csengo_http_requests_total";
 pub const HTTP_DURATION: &str = "csengo_http_request_duration_seconds";
 
-// 1ms to 1h
+// 1ms to 1h, and up to 1s early
 const DRIFT_BUCKETS: &[f64] = &[
-    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0, 30.0, 60.0, 300.0, 900.0, 1800.0,
-    3600.0,
+    -1.0, -0.1, -0.01, -0.001, 0.0, 0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0,
+    30.0, 60.0, 300.0, 900.0, 1800.0, 3600.0,
 ];
 
 // 0.1ms to 5s
@@ -80,16 +85,28 @@ pub fn init() -> PrometheusHandle {
         PLAYBACK_QUEUE_SIZE,
         "Number of tracks in the playback queue"
     );
+    describe_counter!(
+        PLAYBACK_SUPPRESSED,
+        "Total number of scheduled plays that were skipped on purpose"
+    );
 
     describe_counter!(TASKS_CREATED, "Total number of tasks created");
     describe_counter!(TASKS_FAILED, "Total number of failed task executions");
     describe_gauge!(
         TASKS_ACTIVE,
-        "Number of currently active scheduled/recurring tasks"
+        "Number of currently active scheduled/recurring/cron tasks"
+    );
+    describe_counter!(
+        TASKS_MISSED,
+        "Total number of missed plays, by what was done about them"
+    );
+    describe_gauge!(
+        SCHEDULE_PAUSED,
+        "Whether all schedules are paused (1) or not (0)"
     );
     describe_histogram!(
         TASK_DRIFT,
-        "Difference between scheduled and actual execution time in seconds"
+        "Difference between scheduled and actual execution time in seconds, negative if early"
     );
 
     describe_counter!(DB_OPS_TOTAL, "Total number of database operations");
@@ -112,6 +129,14 @@ pub fn init() -> PrometheusHandle {
     describe_histogram!(HTTP_DURATION, "Duration of HTTP requests in seconds");
 
     describe_counter!(AUDIO_ERRORS, "Total number of audio device errors");
+    describe_gauge!(
+        OUTPUT_UP,
+        "Whether the device of an output can be played on (1) or not (0)"
+    );
+    describe_counter!(
+        PAUSES_OVERRIDDEN,
+        "Total number of paused tracks put back in the queue for a planned track"
+    );
 
     gauge!(BUILD_INFO, "git_ref" => GIT_REF, "db_version" => DB_VERSION.to_string()).set(1.0);
 
@@ -120,6 +145,8 @@ pub fn init() -> PrometheusHandle {
     gauge!(PLAYBACK_QUEUE_SIZE).set(0.0);
     gauge!(TASKS_ACTIVE, "type" => "scheduled").set(0.0);
     gauge!(TASKS_ACTIVE, "type" => "recurring").set(0.0);
+    gauge!(TASKS_ACTIVE, "type" => "cron").set(0.0);
+    gauge!(SCHEDULE_PAUSED).set(0.0);
     gauge!(DB_FILES_COUNT).set(0.0);
     gauge!(DB_FILES_BYTES).set(0.0);
 
@@ -135,6 +162,10 @@ pub fn record_playback_failure(task_type: &str, task_name: &str) {
     counter!(TASKS_FAILED, "task_type" => task_type.to_string(), "task_name" => task_name.to_string()).increment(1);
 }
 
+pub fn record_playback_suppressed(task_type: &str, task_name: &str, reason: &str) {
+    counter!(PLAYBACK_SUPPRESSED, "task_type" => task_type.to_string(), "task_name" => task_name.to_string(), "reason" => reason.to_string()).increment(1);
+}
+
 pub fn record_playback_seconds(task_name: &str, seconds: f64) {
     counter!(PLAYBACK_SECONDS, "task_name" => task_name.to_string()).increment(seconds as u64);
 }
@@ -163,6 +194,14 @@ pub fn record_drift(task_type: &str, task_name: &str, drift_seconds: f64) {
     histogram!(TASK_DRIFT, "task_type" => task_type.to_string(), "task_name" => task_name.to_string()).record(drift_seconds);
 }
 
+pub fn set_paused(paused: bool) {
+    gauge!(SCHEDULE_PAUSED).set(if paused { 1.0 } else { 0.0 });
+}
+
+pub fn record_task_missed(task_type: &str, task_name: &str, action: &str) {
+    counter!(TASKS_MISSED, "task_type" => task_type.to_string(), "task_name" => task_name.to_string(), "action" => action.to_string()).increment(1);
+}
+
 pub fn record_db_operation(operation: &str, table: &str, duration_seconds: f64) {
     counter!(DB_OPS_TOTAL, "operation" => operation.to_string(), "table" => table.to_string())
         .increment(1);
@@ -190,17 +229,70 @@ pub fn record_audio_error() {
     counter!(AUDIO_ERRORS).increment(1);
 }
 
+pub fn set_output_up(output: &str, up: bool) {
+    gauge!(OUTPUT_UP, "output" => output.to_string()).set(if up { 1.0 } else { 0.0 });
+}
+
+pub fn record_pause_overridden(output: &str) {
+    counter!(PAUSES_OVERRIDDEN, "output" => output.to_string()).increment(1);
+}
+
 // normalize HTTP paths to avoid high cardinality
 fn normalize_path(path: &str) -> String {
+    if path.starts_with("/htmx/task/") && path.ends_with("/edit") {
+        return "/htmx/task/:id/edit".to_string();
+    }
+    if path.starts_with("/htmx/task/") && path.ends_with("/exception") {
+        return "/htmx/task/:id/exception".to_string();
+    }
+    if path.starts_with("/htmx/exception/") && path.len() > 16 {
+        return "/htmx/exception/:id".to_string();
+    }
     if path.starts_with("/htmx/task/") && path.len() > 11 {
         return "/htmx/task/:id".to_string();
     }
+    if path.starts_with("/htmx/calendar/") && path.len() > 15 {
+        return "/htmx/calendar/:id".to_string();
+    }
+    if path.starts_with("/htmx/profile/") && path.len() > 14 {
+        return "/htmx/profile/:name".to_string();
+    }
+    if path.starts_with("/htmx/override/") && path.len() > 15 {
+        return "/htmx/override/:date".to_string();
+    }
+    if path.starts_with("/htmx/playlist/") && path.contains("/item/") {
+        return "/htmx/playlist/:name/item/:id".to_string();
+    }
+    if path.starts_with("/htmx/playlist/") && path.ends_with("/item") {
+        return "/htmx/playlist/:name/item".to_string();
+    }
+    if path.starts_with("/htmx/playlist/") && path.len() > 15 {
+        return "/htmx/playlist/:name".to_string();
+    }
+    if path.starts_with("/htmx/file/") && path.ends_with("/gain") {
+        return "/htmx/file/:fname/gain".to_string();
+    }
     if path.starts_with("/htmx/file/") && path.len() > 11 {
         return "/htmx/file/:fname".to_string();
     }
+    if path.starts_with("/htmx/queue/") && path.ends_with("/position") {
+        return "/htmx/queue/:id/position".to_string();
+    }
+    if path.starts_with("/htmx/queue/") && path.len() > 12 {
+        return "/htmx/queue/:id".to_string();
+    }
+    if path.starts_with("/api/queue/") && path.ends_with("/position") {
+        return "/api/queue/:id/position".to_string();
+    }
+    if path.starts_with("/api/queue/") && path.len() > 11 {
+        return "/api/queue/:id".to_string();
+    }
     if path.starts_with("/api/file/") && path.len() > 10 {
         return "/api/file/:fname".to_string();
     }
+    if path.starts_with("/api/output/") && path.ends_with("/device") {
+        return "/api/output/:name/device".to_string();
+    }
     if path.starts_with("/static/") {
         return "/static/*path".to_string();
     }
@@ -1,15 +1,20 @@
 use crate::{
+    calendar,
+    clock::Clock,
     db,
     server::err_to_reply,
-    sink::{Controller, Track},
-    Task,
+    sink::{Controller, Health, QueueItem, Track},
+    zones::{self, Route},
+    Fade, File, Loudness, Playlist, Task, Urgency,
 };
 use anyhow::Result;
 use axum::{http::StatusCode, response::Response};
 use bytes::Bytes;
+use chrono::{DateTime, Local};
+use ebur128::{EbuR128, Mode};
 use metrics_exporter_prometheus::PrometheusHandle;
 use rodio::{
-    source::{ChannelVolume, SineWave, UniformSourceIterator},
+    source::{ChannelVolume, Empty, SineWave, UniformSourceIterator},
     Decoder, Source,
 };
 use rusqlite::Connection;
@@ -17,36 +22,41 @@ use serde::Serialize;
 use std::{
     collections::HashMap,
     io::Cursor,
+    ops::RangeInclusive,
     sync::Arc,
     time::{Duration, Instant},
 };
-use tokio::sync::{
-    oneshot,
-    watch::{Receiver, Ref},
-    Mutex, MutexGuard,
-};
+use tokio::sync::{oneshot, Mutex, MutexGuard};
+
+/// the allowed gain offsets of tasks and files, in dB
+pub const GAIN_RANGE: RangeInclusive<f32> = -30.0..=20.0;
+/// the allowed lengths of fades, in seconds
+pub const FADE_RANGE: RangeInclusive<f32> = 0.0..=10.0;
+/// normalization doesn't boost files above this, in dBTP
+const MAX_TRUE_PEAK: f64 = -1.0;
 
 #[derive(Clone)]
 pub struct Player {
-    controller: Controller,
+    /// one for each output, in the order of the config
+    controllers: Arc<Vec<Controller>>,
     pub conn: db::Db,
-    np_rx: Receiver<Option<NowPlaying>>,
     cancel_map: Arc<Mutex<HashMap<String, oneshot::Sender<()>>>>,
     metrics: Arc<PrometheusHandle>,
+    pub clock: Arc<dyn Clock>,
 }
 impl Player {
     pub fn new(
-        controller: Controller,
-        np_rx: Receiver<Option<NowPlaying>>,
+        controllers: Vec<Controller>,
         conn: db::Db,
         metrics: PrometheusHandle,
+        clock: Arc<dyn Clock>,
     ) -> Self {
         Player {
-            controller,
+            controllers: Arc::new(controllers),
             conn,
-            np_rx,
             cancel_map: Arc::new(Mutex::new(HashMap::new())),
             metrics: Arc::new(metrics),
+            clock,
         }
     }
 
@@ -54,59 +64,237 @@ impl Player {
         self.metrics.render()
     }
 
+    fn controller(&self, output: &str) -> Result<&Controller> {
+        self.controllers
+            .iter()
+            .find(|c| c.name == output)
+            .ok_or_else(|| anyhow::anyhow!("Output not found: {output}"))
+    }
+    /// where to queue a play. it's kept even if the device is lost, to play once it's back
+    fn queue_on(&self, output: &str, fname: &str) -> Result<&Controller> {
+        let c = self.controller(output)?;
+        if !c.health().ok {
+            warn!("{fname}: {output} isn't working, queued until it's back");
+        }
+        Ok(c)
+    }
+    /// the outputs, with their devices and what they're playing
+    pub fn outputs(&self) -> Vec<OutputStatus> {
+        self.controllers
+            .iter()
+            .map(|c| OutputStatus {
+                name: c.name.clone(),
+                device: c.device(),
+                playing: c.np_rx().borrow().as_ref().map(|np| np.name.clone()),
+                health: c.health(),
+            })
+            .collect()
+    }
+    /// the names of the outputs whose device isn't working
+    pub fn outputs_down(&self) -> Vec<String> {
+        self.controllers
+            .iter()
+            .filter(|c| !c.health().ok)
+            .map(|c| c.name.clone())
+            .collect()
+    }
+    /// switch an output to another device, `None` for the default one, and save it
+    pub async fn set_device(&self, output: &str, device: Option<String>) -> Result<()> {
+        let c = self.controller(output)?.clone();
+        let d = device.clone();
+        tokio::task::spawn_blocking(move || c.set_device(d)).await??;
+        db::set_output_device(&*self.conn.lock().await, output, device.as_deref())?;
+        Ok(())
+    }
+
     pub fn stop(&self) {
-        self.controller.stop();
+        for c in self.controllers.iter() {
+            c.stop();
+        }
     }
 
-    pub async fn play_file(&self, fname: &str, priority: bool) -> Result<()> {
-        let file = db::get_file(&*self.conn.lock().await, fname)?;
-        self.play_buf(file.data, fname, priority)
+    /// an output, or every one if it's `None`
+    fn selected(&self, output: Option<&str>) -> Result<Vec<&Controller>> {
+        match output {
+            Some(output) => Ok(vec![self.controller(output)?]),
+            None => Ok(self.controllers.iter().collect()),
+        }
+    }
+    /// the queue of each output
+    pub fn queue(&self) -> Vec<(String, Vec<QueueItem>)> {
+        self.controllers
+            .iter()
+            .map(|c| (c.name.clone(), c.queue(self.clock.now())))
+            .collect()
+    }
+    /// remove a queued track from whichever output it's on, false if it's not queued
+    pub fn remove_queued(&self, id: u64) -> bool {
+        self.controllers.iter().any(|c| c.remove(id))
+    }
+    /// move a queued track to `position` in its queue, false if it's not queued
+    pub fn reorder_queued(&self, id: u64, position: usize) -> bool {
+        self.controllers.iter().any(|c| c.reorder(id, position))
+    }
+    /// hold the track playing on `output`, or on every output, where it is.
+    /// urgent tracks can't be paused, false if nothing was
+    pub fn pause(&self, output: Option<&str>) -> Result<bool> {
+        let mut paused = false;
+        for c in self.selected(output)? {
+            let playing = c.np_rx().borrow().as_ref().map(|np| np.urgency);
+            if playing.is_some_and(|u| !u.is_urgent()) {
+                c.pause();
+                paused = true;
+            }
+        }
+        Ok(paused)
+    }
+    /// continue the paused tracks from where they were
+    pub fn resume(&self, output: Option<&str>) -> Result<()> {
+        for c in self.selected(output)? {
+            c.resume();
+        }
+        Ok(())
+    }
+    /// fade out the current track, and go on with the queue
+    pub fn skip(&self, output: Option<&str>) -> Result<()> {
+        for c in self.selected(output)? {
+            c.skip();
+        }
+        Ok(())
+    }
+    /// empty the queue, but let the current track finish
+    pub fn clear_queue(&self, output: Option<&str>) -> Result<()> {
+        for c in self.selected(output)? {
+            c.clear();
+        }
+        Ok(())
     }
-    pub fn play_buf(&self, buf: Bytes, fname: &str, priority: bool) -> Result<()> {
-        let src = Decoder::new(Cursor::new(buf))?;
-        // https://github.com/RustAudio/rodio/pull/493: ChannelVolume distorts the audio if the
-        // input sample rate isn't constant, so we manually normalize it here.
-        let src: UniformSourceIterator<_, f32> = UniformSourceIterator::new(src, 2, 48000);
-        let src = ChannelVolume::new(src, vec![0.5, if priority { 0.5 } else { 0.0 }]);
 
-        self.controller.append(Track {
-            src: Box::new(src),
-            name: Some(fname.into()),
-        });
+    pub fn volume(&self) -> f32 {
+        self.controllers[0].volume()
+    }
+    /// set and save the master volume of every output, between 0 and 1
+    pub fn set_volume(&self, conn: &Connection, volume: f32) -> Result<()> {
+        if !(0.0..=1.0).contains(&volume) {
+            anyhow::bail!("Volume must be between 0 and 1");
+        }
+        db::set_volume(conn, volume)?;
+        for c in self.controllers.iter() {
+            c.set_volume(volume);
+        }
         Ok(())
     }
 
-    pub fn now_playing(&self) -> Ref<'_, Option<NowPlaying>> {
-        self.np_rx.borrow()
+    pub fn stop_fade(&self) -> f32 {
+        self.controllers[0].stop_fade()
     }
-    pub fn np_realtime(&self) -> Receiver<Option<NowPlaying>> {
-        let mut rx = self.np_rx.clone();
-        rx.borrow_and_update(); // to make sure to wait for the next value
-        rx
+    /// set and save how long a track fades out when it's stopped, in seconds
+    pub fn set_stop_fade(&self, conn: &Connection, secs: f32) -> Result<()> {
+        if !FADE_RANGE.contains(&secs) {
+            anyhow::bail!("The fade must be between 0 and 10 seconds");
+        }
+        db::set_stop_fade(conn, secs)?;
+        for c in self.controllers.iter() {
+            c.set_stop_fade(secs);
+        }
+        Ok(())
+    }
+
+    /// play a file of a task, or every file of a playlist, in each of its zones
+    pub fn play_file(&self, conn: &Connection, fname: &str, task: &Task) -> Result<()> {
+        for route in zones::routes(task.get_zones()) {
+            let c = self.queue_on(route.output, fname)?;
+            for mut track in tracks(conn, fname, &route, task.get_gain(), task.get_fade())? {
+                track.urgency = task.get_urgency();
+                c.append(track);
+            }
+        }
+        Ok(())
     }
-    pub fn np_stream(&self) -> impl futures_util::Stream<Item = Option<NowPlaying>> {
-        fn inner(r: tokio::sync::watch::Ref<'_, Option<NowPlaying>>) -> Option<NowPlaying> {
-            r.to_owned()
+    /// queue a file of a task to start exactly at `time`, with its start already decoded
+    pub fn play_file_at(
+        &self,
+        conn: &Connection,
+        fname: &str,
+        task: &Task,
+        time: DateTime<Local>,
+    ) -> Result<()> {
+        let start = Instant::now() + (time - self.clock.now()).to_std().unwrap_or_default();
+        for route in zones::routes(task.get_zones()) {
+            let c = self.queue_on(route.output, fname)?;
+            let mut tracks = tracks(conn, fname, &route, task.get_gain(), task.get_fade())?;
+
+            // the rest of a playlist follows right after the first one
+            let first = &mut tracks[0];
+            first.src = Box::new(Prefetched::new(std::mem::replace(
+                &mut first.src,
+                Box::new(Empty::new()),
+            )));
+            first.start = Some(start);
+            first.task = Some((task.get_type(), task.get_name().to_owned()));
+
+            for mut track in tracks {
+                track.urgency = task.get_urgency();
+                c.append(track);
+            }
         }
-        let mut rx = self.np_rx.clone();
+        Ok(())
+    }
+
+    /// the outputs playing something, and what
+    pub fn now_playing(&self) -> Vec<(String, NowPlaying)> {
+        self.controllers
+            .iter()
+            .filter_map(|c| Some((c.name.clone(), c.np_rx().borrow().clone()?)))
+            .collect()
+    }
+    /// wait until a track starts or ends, or a device is lost or back, on any of the outputs
+    pub async fn np_changed(&self) {
+        let mut rxs: Vec<_> = self.controllers.iter().map(Controller::np_rx).collect();
+        let (res, ..) =
+            futures_util::future::select_all(rxs.iter_mut().map(|rx| Box::pin(rx.changed()))).await;
+        // the senders live as long as the outputs, if one's gone it won't change anymore
+        if let Err(e) = res {
+            warn!("an output stopped reporting what it plays: {e}");
+            std::future::pending::<()>().await;
+        }
+    }
+    /// what's playing, and the outputs that are down
+    pub fn np_stream(
+        &self,
+    ) -> impl futures_util::Stream<Item = (Vec<(String, NowPlaying)>, Vec<String>)> {
+        let p = self.clone();
         async_stream::stream! {
-            while rx.changed().await.is_ok() {
-                let data = inner(rx.borrow());
-                yield data;
+            loop {
+                p.np_changed().await;
+                yield (p.now_playing(), p.outputs_down());
             }
         }
     }
+    /// whether there's more than one output, so they need to be told apart
+    pub fn has_outputs(&self) -> bool {
+        self.controllers.len() > 1
+    }
 
+    /// a beep on every output
     pub fn playtest(&self) {
-        // taken from https://docs.rs/rodio
-        // add a dummy source for the sake of the example.
-        let source = SineWave::new(880.0)
-            .take_duration(Duration::from_secs_f32(1.0))
-            .amplify(0.20);
-        self.controller.append(Track {
-            src: Box::new(source),
-            name: Some("playtest".into()),
-        });
+        for c in self.controllers.iter() {
+            // taken from https://docs.rs/rodio
+            // add a dummy source for the sake of the example.
+            let source = SineWave::new(880.0)
+                .take_duration(Duration::from_secs_f32(1.0))
+                .amplify(0.20);
+            c.append(Track {
+                id: 0,
+                src: Box::new(source),
+                name: Some("playtest".into()),
+                start: None,
+                task: None,
+                playlist: None,
+                urgency: Urgency::Normal,
+                played: Duration::ZERO,
+            });
+        }
     }
 
     pub async fn db_name<T: Serialize>(
@@ -134,12 +322,266 @@ impl Player {
 
     pub async fn lock(&self) -> PlayerLock<'_> {
         let lock = self.conn.lock().await;
-        PlayerLock { lock }
+        PlayerLock {
+            lock,
+            clock: self.clock.clone(),
+        }
     }
 }
 
+/// the tracks of a file, or of every file in a playlist, never empty.
+/// `gain` is added to the gain of each file
+/// every file is faded in and out, even in a playlist
+fn tracks(
+    conn: &Connection,
+    name: &str,
+    route: &Route,
+    gain: f32,
+    fade: Fade,
+) -> Result<Vec<Track>> {
+    let target = db::get_target_loudness(conn)?;
+    let gain_of = |file: &File| gain + file.gain + normalization(file.loudness, target);
+    // the fade-out is measured back from the end, the length is saved so it's not decoded again
+    let len_of = |name: &str| -> Result<Option<Duration>> {
+        match fade.fade_out > 0.0 {
+            true => Ok(Some(db::get_file_duration(conn, name)?)),
+            false => Ok(None),
+        }
+    };
+
+    let Some(playlist) = db::get_playlist(conn, name)? else {
+        let file = db::get_file(conn, name)?;
+        let len = len_of(name)?;
+        return Ok(vec![Track {
+            id: 0,
+            src: Box::new(decode(file.data.clone(), route, gain_of(&file), fade, len)?),
+            name: Some(name.into()),
+            start: None,
+            task: None,
+            playlist: None,
+            urgency: Urgency::Normal,
+            played: Duration::ZERO,
+        }]);
+    };
+    if playlist.items.is_empty() {
+        anyhow::bail!("Playlist {name} is empty");
+    }
+
+    let len = playlist.items.len();
+    // the gap after a file is played as silence before the next one
+    let mut gap = Duration::ZERO;
+    playlist
+        .items
+        .into_iter()
+        .enumerate()
+        .map(|(index, item)| {
+            let file = db::get_file(conn, &item.file_name)?;
+            let file_len = len_of(&item.file_name)?;
+            let src = decode(file.data.clone(), route, gain_of(&file), fade, file_len)?.delay(gap);
+            gap = Duration::from_secs_f64(item.gap);
+            Ok(Track {
+                id: 0,
+                src: Box::new(src),
+                name: Some(item.file_name),
+                start: None,
+                task: None,
+                playlist: Some(Position {
+                    playlist: name.to_owned(),
+                    index,
+                    len,
+                }),
+                urgency: Urgency::Normal,
+                played: Duration::ZERO,
+            })
+        })
+        .collect()
+}
+
+/// `gain` is in dB, the master volume is applied later by the sink
+/// `len` is the length of the file, only needed for a fade-out
+fn decode(
+    buf: Bytes,
+    route: &Route,
+    gain: f32,
+    fade: Fade,
+    len: Option<Duration>,
+) -> Result<impl Source<Item = f32> + Send + Sync> {
+    let src = Decoder::new(Cursor::new(buf))?;
+    // https://github.com/RustAudio/rodio/pull/493: ChannelVolume distorts the audio if the
+    // input sample rate isn't constant, so we manually normalize it here.
+    let src: UniformSourceIterator<_, f32> = UniformSourceIterator::new(src, 2, 48000);
+    let level = 0.5 * db_to_amplitude(gain);
+    let volumes = (0..route.channels)
+        .map(|c| {
+            if route.mask & (1 << c) != 0 {
+                level
+            } else {
+                0.0
+            }
+        })
+        .collect();
+    let src = ChannelVolume::new(src, volumes);
+
+    // in samples
+    let rate = src.sample_rate() as f32 * src.channels() as f32;
+    let fade_out = len.map(|len| {
+        let end = (len.as_secs_f32() * rate) as u64;
+        (end.saturating_sub((fade.fade_out * rate) as u64), end)
+    });
+    Ok(Fader {
+        src,
+        pos: 0,
+        fade_in: (fade.fade_in * rate) as u64,
+        fade_out,
+    })
+}
+
+/// fades the start and the end of a source, positions are in samples
+struct Fader<S> {
+    src: S,
+    pos: u64,
+    /// the length of the fade-in
+    fade_in: u64,
+    /// where the fade-out starts and ends
+    fade_out: Option<(u64, u64)>,
+}
+impl<S: Source<Item = f32>> Iterator for Fader<S> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let sample = self.src.next()?;
+        let mut level = 1.0;
+        if self.pos < self.fade_in {
+            level = self.pos as f32 / self.fade_in as f32;
+        }
+        if let Some((start, end)) = self.fade_out.filter(|(start, _)| self.pos >= *start) {
+            level *= end.saturating_sub(self.pos) as f32 / (end - start).max(1) as f32;
+        }
+        self.pos += 1;
+        Some(sample * level)
+    }
+}
+impl<S: Source<Item = f32>> Source for Fader<S> {
+    fn current_frame_len(&self) -> Option<usize> {
+        self.src.current_frame_len()
+    }
+    fn channels(&self) -> u16 {
+        self.src.channels()
+    }
+    fn sample_rate(&self) -> u32 {
+        self.src.sample_rate()
+    }
+    fn total_duration(&self) -> Option<Duration> {
+        self.src.total_duration()
+    }
+}
+
+fn db_to_amplitude(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+/// the gain in dB that brings a file to the `target` loudness
+fn normalization(loudness: Option<Loudness>, target: Option<f64>) -> f32 {
+    let (Some(loudness), Some(target)) = (loudness, target) else {
+        return 0.0;
+    };
+    let gain = target - loudness.integrated;
+    // quiet files are only boosted as long as they don't clip
+    let gain = if gain > 0.0 {
+        gain.min((MAX_TRUE_PEAK - loudness.true_peak).max(0.0))
+    } else {
+        gain
+    };
+    gain as f32
+}
+
+/// the EBU R128 integrated loudness and true peak of an audio file, `None` if it's silent
+pub fn measure(buf: Bytes) -> Result<Option<Loudness>> {
+    let src = Decoder::new(Cursor::new(buf))?;
+    let (channels, rate) = (src.channels(), src.sample_rate());
+    let src: UniformSourceIterator<_, f32> = UniformSourceIterator::new(src, channels, rate);
+
+    let mut meter = EbuR128::new(channels as u32, rate, Mode::I | Mode::TRUE_PEAK)?;
+    let mut chunk = Vec::with_capacity(4096 * channels as usize);
+    for sample in src {
+        chunk.push(sample);
+        if chunk.len() == chunk.capacity() {
+            meter.add_frames_f32(&chunk)?;
+            chunk.clear();
+        }
+    }
+    // drop an incomplete last frame
+    chunk.truncate(chunk.len() - chunk.len() % channels as usize);
+    meter.add_frames_f32(&chunk)?;
+
+    let integrated = meter.loudness_global()?;
+    if !integrated.is_finite() {
+        return Ok(None);
+    }
+    let mut peak = 0f64;
+    for channel in 0..channels as u32 {
+        peak = peak.max(meter.true_peak(channel)?);
+    }
+    Ok(Some(Loudness {
+        integrated,
+        true_peak: 20.0 * peak.log10(),
+    }))
+}
+
+/// a source with its first samples decoded ahead of time,
+/// so the sink can start it without waiting for the decoder
+struct Prefetched<S> {
+    head: std::vec::IntoIter<f32>,
+    rest: S,
+}
+impl<S: Source<Item = f32>> Prefetched<S> {
+    /// how much to decode ahead
+    const LEN: Duration = Duration::from_secs(2);
+
+    fn new(mut rest: S) -> Self {
+        let n = (Self::LEN.as_secs_f64() * rest.sample_rate() as f64) as usize
+            * rest.channels() as usize;
+        let head = rest.by_ref().take(n).collect::<Vec<_>>().into_iter();
+        Self { head, rest }
+    }
+}
+impl<S: Source<Item = f32>> Iterator for Prefetched<S> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        self.head.next().or_else(|| self.rest.next())
+    }
+}
+impl<S: Source<Item = f32>> Source for Prefetched<S> {
+    // the format is uniform, so frames don't matter
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+    fn channels(&self) -> u16 {
+        self.rest.channels()
+    }
+    fn sample_rate(&self) -> u32 {
+        self.rest.sample_rate()
+    }
+    fn total_duration(&self) -> Option<Duration> {
+        self.rest.total_duration()
+    }
+}
+
+/// the length of an audio file, decoding all of it if the format doesn't tell
+pub fn track_length(buf: Bytes) -> Result<Duration> {
+    let src = Decoder::new(Cursor::new(buf))?;
+    if let Some(len) = src.total_duration() {
+        return Ok(len);
+    }
+    let rate = src.channels() as f64 * src.sample_rate() as f64;
+    let samples = src.count() as f64;
+    Ok(Duration::from_secs_f64(samples / rate))
+}
+
 pub struct PlayerLock<'a> {
     pub lock: MutexGuard<'a, Connection>,
+    pub clock: Arc<dyn Clock>,
 }
 impl PlayerLock<'_> {
     pub fn list_tasks(&mut self) -> Result<Vec<Task>, Response> {
@@ -152,6 +594,26 @@ impl PlayerLock<'_> {
             )
         })
     }
+    pub fn list_calendar(&mut self) -> Result<Vec<calendar::Entry>, Response> {
+        db::list_calendar(&self.lock).map_err(|e| {
+            err_to_reply(
+                e.into(),
+                "List calendar",
+                "Failed to get calendar",
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        })
+    }
+    pub fn list_playlists(&mut self) -> Result<Vec<Playlist>, Response> {
+        db::list_playlists(&self.lock).map_err(|e| {
+            err_to_reply(
+                e.into(),
+                "List playlists",
+                "Failed to get playlists",
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        })
+    }
     pub fn list_files(&mut self) -> Result<Vec<String>, Response> {
         db::list_files(&self.lock).map_err(|e| {
             err_to_reply(
@@ -168,4 +630,32 @@ pub struct NowPlaying {
     pub name: String,
     pub len: Option<Duration>,
     pub started: Instant,
+    /// where it's held, `None` while it's playing
+    pub paused: Option<Duration>,
+    pub playlist: Option<Position>,
+    pub urgency: Urgency,
+    /// the track ducked under this one, or the one it interrupted, which continues after it
+    pub under: Option<String>,
+}
+#[derive(Debug, Clone, Serialize)]
+pub struct OutputStatus {
+    pub name: String,
+    /// `None` for the default device
+    pub device: Option<String>,
+    /// the name of the track playing
+    pub playing: Option<String>,
+    pub health: Health,
+}
+impl NowPlaying {
+    /// how much of it was played, it stands still while it's paused
+    pub fn elapsed(&self) -> Duration {
+        self.paused.unwrap_or_else(|| self.started.elapsed())
+    }
+}
+/// where a track is in the playlist it's played from
+#[derive(Debug, Clone)]
+pub struct Position {
+    pub playlist: String,
+    pub index: usize,
+    pub len: usize,
 }
@@ -1,158 +1,1174 @@
-use crate::{db, mail, metrics as m, player::Player, templates::dur_human, Task};
-use chrono::{DateTime, Local, NaiveTime, TimeZone};
-use tokio::{select, time::Duration};
+use crate::{
+    clock::Clock, db, mail, metrics as m, player::Player, templates::dur_human, zones, Action,
+    Anchor, Exception, Missed, Task, Urgency,
+};
+use chrono::{
+    DateTime, Datelike, Local, LocalResult, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Weekday,
+};
+use croner::Cron;
+use rusqlite::Connection;
+use serde::Serialize;
+use std::collections::HashMap;
+use tokio::{select, sync::oneshot, time::Duration};
 
-// get next time to play for recurring tasks
-// handles daylight savings time by subtracting timezone-aware chrono DateTimes
-fn duration_until_next(times: &[NaiveTime]) -> (chrono::Duration, DateTime<Local>) {
-    let now = Local::now();
+/// plays later than this count as missed, not just late
+const MISSED_AFTER_SECS: i64 = 10;
+/// how far back to look for missed plays on startup
+const CATCH_UP_DAYS: i64 = 7;
+/// how long expired recurring tasks are kept around before archiving them
+const ARCHIVE_AFTER_DAYS: i64 = 30;
+/// the longest uninterrupted sleep of a task
+const WAKE_INTERVAL: Duration = Duration::from_secs(60);
+/// how early tasks wake up to queue their track, to start exactly on time.
+/// has to cover the silence chunks of the idle sink, and decoding the start of the file
+const PREROLL: chrono::Duration = chrono::Duration::seconds(2);
+
+/// a single play of a task
+#[derive(Debug)]
+pub struct Occurrence<Tz: TimeZone = Local> {
+    pub time: DateTime<Tz>,
+    /// replaces the file of the task for this occurrence
+    pub file_name: Option<String>,
+}
+
+/// the plays of a recurring task on a given day, with its exceptions applied
+fn occurrences_on<Tz: TimeZone>(
+    tz: &Tz,
+    date: NaiveDate,
+    times: &[NaiveTime],
+    days: &[Weekday],
+    exceptions: &[Exception],
+) -> Vec<Occurrence<Tz>> {
+    if !days.contains(&date.weekday()) {
+        return Vec::new();
+    }
 
     times
         .iter()
-        .filter_map(|&target| {
-            let today = now.date_naive().and_time(target);
-
-            if let Some(dt) = Local.from_local_datetime(&today).single() {
-                if dt > now {
-                    return Some((dt - now, dt));
-                }
-            }
+        .filter_map(|&time| {
+            let exception = exceptions.iter().find(|e| e.date == date && e.time == time);
+            let (time, file_name) = match exception.map(|e| &e.action) {
+                None => (time, None),
+                Some(Action::Skip) => return None,
+                Some(Action::Move { to }) => (*to, None),
+                Some(Action::Replace { file_name }) => (time, Some(file_name.to_owned())),
+            };
+            let time = local_time(tz, date.and_time(time))?;
+            Some(Occurrence { time, file_name })
+        })
+        .collect()
+}
 
-            // fall back to tomorrow
-            let tomorrow = (now + chrono::Duration::days(1))
-                .date_naive()
-                .and_time(target);
+/// resolve a wall clock time in the local timezone.
+/// on a DST fall-back it plays the first time, and times skipped by spring-forward are
+/// shifted forward by the length of the gap.
+fn local_time<Tz: TimeZone>(tz: &Tz, time: NaiveDateTime) -> Option<DateTime<Tz>> {
+    match tz.from_local_datetime(&time) {
+        LocalResult::Single(t) => Some(t),
+        // not always in order
+        LocalResult::Ambiguous(a, b) => Some(a.min(b)),
+        LocalResult::None => tz
+            .from_local_datetime(&(time + chrono::Duration::hours(1)))
+            .earliest(),
+    }
+}
 
-            Local
-                .from_local_datetime(&tomorrow)
-                .single()
-                .map(|dt| (dt - now, dt))
+// get next time to play for recurring tasks, `None` if its validity has ended
+// handles daylight savings time by subtracting timezone-aware chrono DateTimes
+pub fn duration_until_next<Tz: TimeZone>(
+    now: DateTime<Tz>,
+    times: &[NaiveTime],
+    days: &[Weekday],
+    exceptions: &[Exception],
+    valid_from: Option<NaiveDate>,
+    valid_until: Option<NaiveDate>,
+) -> Option<(chrono::Duration, Occurrence<Tz>)> {
+    let today = now.date_naive();
+    // a week later the same weekday comes around again, so 8 days always contain a match,
+    // unless all of them are skipped, in which case we look further
+    valid_from
+        .map_or(today, |from| from.max(today))
+        .iter_days()
+        .take_while(|date| valid_until.is_none_or(|until| *date <= until))
+        .flat_map(|date| {
+            let mut occurrences = occurrences_on(&now.timezone(), date, times, days, exceptions);
+            occurrences.sort_by(|a, b| a.time.cmp(&b.time));
+            occurrences
         })
-        .min_by_key(|(duration, _)| *duration)
-        .expect("at least one valid time should exist")
+        .find(|o| o.time > now)
+        .map(|o| (o.time.clone() - now, o))
 }
 
 pub async fn schedule(task: Task, player: Player) -> anyhow::Result<()> {
-    match task {
+    match &task {
         Task::Now {
-            name,
-            file_name,
-            priority,
+            name, file_name, ..
         } => {
-            if let Err(e) = player.play_file(&file_name, priority).await {
-                m::record_playback_failure("now", &name);
+            if let Err(e) = player.play_file(&*player.conn.lock().await, file_name, &task) {
+                m::record_playback_failure("now", name);
                 return Err(e);
             }
-            m::record_playback_success("now", &name);
+            m::record_playback_success("now", name);
         }
 
-        Task::Scheduled {
-            name,
-            priority,
-            file_name,
-            time,
-        } => {
+        Task::Scheduled { time, .. } => {
+            let time = *time;
             // return if the date was in the past
             // there has to be a better way to do this
-            (time - Local::now()).to_std()?;
+            (time - player.clock.now()).to_std()?;
 
             m::inc_active_tasks("scheduled");
 
+            // registered before returning, so the task can be cancelled right away
+            let mut rx = player.create_cancel(task.get_name().to_owned()).await;
+
             tokio::task::spawn(async move {
-                let diff = time - Local::now();
-                debug!("{}: {}", name, dur_human(&diff).0);
-                let diff = diff.to_std().unwrap();
-
-                let rx = player.create_cancel(name.to_owned()).await;
-                if select! {
-                    biased;
-                    _ = rx => true,
-                    _ = tokio::time::sleep(diff) => false,
-                } {
+                let name = task.get_name();
+                let file_name = task.get_file_name();
+                debug!("{}: {}", name, dur_human(&(time - player.clock.now())).0);
+
+                if sleep_until(&*player.clock, time - PREROLL, &mut rx).await {
                     info!("{name}: cancelled");
                     m::dec_active_tasks("scheduled");
                     return;
                 }
+                let now = player.clock.now();
 
-                // record drift
-                let now = Local::now();
-                let drift = (now - time).num_milliseconds().abs() as f64 / 1000.0;
-                m::record_drift("scheduled", &name, drift);
+                let conn = player.conn.lock().await;
+                if is_cancelled(&mut rx) {
+                    info!("{name}: cancelled");
+                    m::dec_active_tasks("scheduled");
+                    return;
+                }
 
-                if let Err(e) = player.play_file(&file_name, priority).await {
+                // clean up under the same lock, so an edit can't slip in between
+                if let Err(e) = db::delete_task(&conn, name) {
+                    error!("{name}: failed to delete task after scheduled play\n{e:#?}");
+                };
+                if player.delete_cancel(name).await.is_none() {
+                    error!("{name}: failed to delete cancel channel");
+                };
+
+                if is_suppressed(&conn, &task, time) {
+                    m::dec_active_tasks("scheduled");
+                    return;
+                }
+
+                // eg. the system was suspended
+                if is_missed(now, time) && !handle_missed(&task, time, now) {
+                    m::dec_active_tasks("scheduled");
+                    return;
+                }
+
+                let res = player.play_file_at(&conn, file_name, &task, time);
+                drop(conn);
+                if let Err(e) = res {
                     error!("error while playing {}:\n{e:#?}", file_name);
-                    m::record_playback_failure("scheduled", &name);
+                    m::record_playback_failure("scheduled", name);
                 } else {
                     // successful play
-                    m::record_playback_success("scheduled", &name);
-                    mail::task_done(&file_name, &time).await;
+                    m::record_playback_success("scheduled", name);
+                    mail::task_done(file_name, &time).await;
                 }
 
                 m::dec_active_tasks("scheduled");
-
-                if let Err(e) = player.db_name(db::delete_task, &name).await {
-                    error!("{name}: failed to delete task after scheduled play\n{e:#?}");
-                };
-                if player.delete_cancel(&name).await.is_none() {
-                    error!("{name}: failed to delete cancel channel");
-                };
             });
         }
 
         Task::Recurring {
             name,
-            priority,
-            file_name,
             time: times,
+            days,
+            valid_from,
+            valid_until,
+            ..
         } => {
-            m::inc_active_tasks("recurring");
+            if times.is_empty() || days.is_empty() {
+                anyhow::bail!("Recurring task needs at least one time and day");
+            }
+            let (task_name, times, days) = (name.clone(), times.clone(), days.clone());
+            let (valid_from, valid_until) = (*valid_from, *valid_until);
 
-            tokio::task::spawn(async move {
-                let mut rx = player.create_cancel(name.to_owned()).await;
-
-                loop {
-                    let (diff, expected_time) = duration_until_next(&times);
-                    let is_tomorrow = expected_time.date_naive() != Local::now().date_naive();
-                    debug!(
-                        "{name} (recurring): {} ({}{})",
-                        dur_human(&diff).0,
-                        expected_time.time().format("%H:%M"),
-                        if is_tomorrow { "+" } else { "" }
+            if let Some(until) = valid_until {
+                spawn_archive(player.clone(), name.clone(), until);
+            }
+
+            spawn_repeating(&task, player, move |conn, now| {
+                let exceptions = db::list_exceptions(conn, Some(&task_name))
+                    .map_err(|e| warn!("{task_name}: failed to get exceptions\n{e:#?}"))
+                    .unwrap_or_default();
+                duration_until_next(now, &times, &days, &exceptions, valid_from, valid_until)
+            })
+            .await;
+        }
+
+        Task::Cron { expr, .. } => {
+            let cron = parse_cron(expr)?;
+
+            spawn_repeating(&task, player, move |_, now| {
+                let time = cron.find_next_occurrence(&now, false).ok()?;
+                Some((
+                    time - now,
+                    Occurrence {
+                        time,
+                        file_name: None,
+                    },
+                ))
+            })
+            .await;
+        }
+    }
+    Ok(())
+}
+
+/// apply the missed policy to the plays missed while csengo wasn't running.
+/// returns false if the task is done and was deleted.
+/// the caller should hold the db lock.
+pub fn catch_up(conn: &Connection, player: &Player, task: &Task) -> anyhow::Result<bool> {
+    let name = task.get_name();
+    let now = player.clock.now();
+    let last_run = || -> anyhow::Result<Option<DateTime<Local>>> {
+        let oldest = now - chrono::Duration::days(CATCH_UP_DAYS);
+        Ok(db::get_last_run(conn, name)?.map(|t| t.max(oldest)))
+    };
+
+    let missed = match task {
+        Task::Now { .. } => None,
+        Task::Scheduled { time, .. } => {
+            if *time > now {
+                return Ok(true);
+            }
+            db::delete_task(conn, name)?;
+            if !is_suppressed(conn, task, *time) && handle_missed(task, *time, now) {
+                play_missed(conn, player, task, None);
+            }
+            return Ok(false);
+        }
+        Task::Recurring {
+            time: times, days, ..
+        } => {
+            let Some(from) = last_run()? else {
+                return Ok(true);
+            };
+            let exceptions = db::list_exceptions(conn, Some(name))?;
+            from.date_naive()
+                .iter_days()
+                .take_while(|date| *date <= now.date_naive())
+                .filter(|date| task.is_valid_on(*date))
+                .flat_map(|date| occurrences_on(&Local, date, times, days, &exceptions))
+                .filter(|o| o.time > from && is_missed(now, o.time))
+                .max_by_key(|o| o.time)
+        }
+        Task::Cron { expr, .. } => {
+            let Some(from) = last_run()? else {
+                return Ok(true);
+            };
+            parse_cron(expr)?
+                .iter_after(from)
+                .take_while(|time| is_missed(now, *time))
+                .last()
+                .map(|time| Occurrence {
+                    time,
+                    file_name: None,
+                })
+        }
+    };
+
+    // only the last one is worth catching up on
+    if let Some(occurrence) = missed {
+        db::set_last_run(conn, name, occurrence.time)?;
+        if !is_suppressed(conn, task, occurrence.time) && handle_missed(task, occurrence.time, now)
+        {
+            play_missed(conn, player, task, occurrence.file_name.as_deref());
+        }
+    }
+    Ok(true)
+}
+
+fn play_missed(conn: &Connection, player: &Player, task: &Task, file_name: Option<&str>) {
+    let (task_type, name) = (task.get_type(), task.get_name());
+    let file_name = file_name.unwrap_or(task.get_file_name());
+    if let Err(e) = player.play_file(conn, file_name, task) {
+        error!("{name}: failed to play missed {task_type} task\n{e:#?}");
+        m::record_playback_failure(task_type, name);
+    } else {
+        m::record_playback_success(task_type, name);
+    }
+}
+
+/// whether a play at `time` was missed, as opposed to just being late
+fn is_missed(now: DateTime<Local>, time: DateTime<Local>) -> bool {
+    now - time > chrono::Duration::seconds(MISSED_AFTER_SECS)
+}
+
+/// apply the missed policy of the task to the play at `time`, returns whether to play it anyway
+fn handle_missed(task: &Task, time: DateTime<Local>, now: DateTime<Local>) -> bool {
+    let name = task.get_name();
+    let late = now - time;
+    let (play, action) = match task.get_missed().unwrap_or_default() {
+        Missed::Drop => (false, "dropped"),
+        Missed::Play { grace } if late <= chrono::Duration::minutes(grace.into()) => {
+            (true, "played")
+        }
+        Missed::Play { .. } => (false, "dropped"),
+        Missed::Notify => {
+            tokio::task::spawn(mail::task_missed(task.get_file_name().to_owned(), time));
+            (false, "notified")
+        }
+    };
+    warn!(
+        "{name}: missed play at {}, late by {}, {action}",
+        time.naive_local(),
+        dur_human(&late).0
+    );
+    m::record_task_missed(task.get_type(), name, action);
+    play
+}
+
+/// whether the pause, the calendar or the bell schedule profile keeps the play at `time` silent
+fn is_suppressed(conn: &Connection, task: &Task, time: DateTime<Local>) -> bool {
+    let (task_type, name) = (task.get_type(), task.get_name());
+    match suppressed_by(conn, task, time) {
+        Ok(Some(reason)) => {
+            info!(
+                "{name}: skipped at {}, silenced by {reason}",
+                time.naive_local()
+            );
+            m::record_playback_suppressed(task_type, name, reason);
+            true
+        }
+        Ok(None) => false,
+        Err(e) => {
+            warn!("{name}: failed to check pause, calendar and profile, playing anyway\n{e:#?}");
+            false
+        }
+    }
+}
+
+/// what keeps the play at `time` silent, if anything
+fn suppressed_by(
+    conn: &Connection,
+    task: &Task,
+    time: DateTime<Local>,
+) -> rusqlite::Result<Option<&'static str>> {
+    // all schedules paused
+    if db::get_paused_until(conn)?.is_some_and(|until| time < until) {
+        return Ok(Some("paused"));
+    }
+    // one-off plays are set for a specific day on purpose
+    if matches!(task, Task::Scheduled { .. }) {
+        return Ok(None);
+    }
+    let date = time.date_naive();
+    // no bells on holidays
+    if db::get_calendar_entry(conn, date)?.is_some() {
+        return Ok(Some("calendar"));
+    }
+    // only ring with the bell schedule in effect
+    if let Some(profile) = task.get_profile() {
+        if db::get_profile_for(conn, date)?.as_deref() != Some(profile) {
+            return Ok(Some("profile"));
+        }
+    }
+    Ok(None)
+}
+
+/// a planned play of a task
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Event {
+    pub time: DateTime<Local>,
+    pub task: String,
+    pub task_type: &'static str,
+    pub file_name: String,
+    pub zones: Vec<String>,
+    pub urgency: Urgency,
+    /// why it won't ring: "paused", "calendar" or "profile"
+    pub suppressed: Option<&'static str>,
+}
+
+/// the most plays a single task can have in a timeline
+const TIMELINE_LIMIT: usize = 10_000;
+
+/// expand every task into its plays between `from` and `to`, in order
+pub fn timeline(
+    conn: &Connection,
+    from: DateTime<Local>,
+    to: DateTime<Local>,
+) -> anyhow::Result<Vec<Event>> {
+    expand(conn, &db::list_tasks(conn)?, from, to)
+}
+
+fn expand(
+    conn: &Connection,
+    tasks: &[Task],
+    from: DateTime<Local>,
+    to: DateTime<Local>,
+) -> anyhow::Result<Vec<Event>> {
+    let exceptions = db::list_exceptions(conn, None)?;
+
+    let mut events = Vec::new();
+    for task in tasks {
+        let occurrences = match task {
+            Task::Now { .. } => continue,
+            Task::Scheduled { time, .. } => vec![Occurrence {
+                time: *time,
+                file_name: None,
+            }],
+            Task::Recurring {
+                name,
+                time: times,
+                days,
+                ..
+            } => {
+                let exceptions = exceptions
+                    .iter()
+                    .filter(|e| &e.task == name)
+                    .cloned()
+                    .collect::<Vec<_>>();
+                from.date_naive()
+                    .iter_days()
+                    .take_while(|date| *date <= to.date_naive())
+                    .filter(|date| task.is_valid_on(*date))
+                    .flat_map(|date| occurrences_on(&Local, date, times, days, &exceptions))
+                    .collect()
+            }
+            Task::Cron { expr, .. } => parse_cron(expr)?
+                .iter_from(from)
+                .take_while(|time| *time < to)
+                .take(TIMELINE_LIMIT)
+                .map(|time| Occurrence {
+                    time,
+                    file_name: None,
+                })
+                .collect(),
+        };
+
+        for o in occurrences
+            .into_iter()
+            .filter(|o| from <= o.time && o.time < to)
+        {
+            events.push(Event {
+                time: o.time,
+                task: task.get_name().to_owned(),
+                task_type: task.get_type(),
+                file_name: o
+                    .file_name
+                    .unwrap_or_else(|| task.get_file_name().to_owned()),
+                zones: task.get_zones().to_vec(),
+                urgency: task.get_urgency(),
+                suppressed: suppressed_by(conn, task, o.time)?,
+            });
+        }
+    }
+    events.sort_by_key(|e| e.time);
+    Ok(events)
+}
+
+/// a play that would start while another one is still playing
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Conflict {
+    pub time: DateTime<Local>,
+    /// the output they'd both play on
+    pub output: String,
+    /// the task still playing
+    pub first: String,
+    /// the task that would be queued behind it
+    pub second: String,
+    /// how late the second one would start, in seconds
+    pub delay: f64,
+}
+
+/// what to do when a new task conflicts with the existing ones
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConflictRule {
+    Warn,
+    Reject,
+}
+impl ConflictRule {
+    /// from $CONFLICTS, `warn` by default
+    pub fn from_env() -> Self {
+        match std::env::var("CONFLICTS").as_deref() {
+            Ok("reject") => ConflictRule::Reject,
+            Ok("warn") | Err(_) => ConflictRule::Warn,
+            Ok(s) => {
+                warn!("invalid $CONFLICTS: {s}, using warn");
+                ConflictRule::Warn
+            }
+        }
+    }
+}
+
+/// the plays between `from` and `to` that overlap, judged by the length of their files
+pub fn conflicts(
+    conn: &Connection,
+    from: DateTime<Local>,
+    to: DateTime<Local>,
+) -> anyhow::Result<Vec<Conflict>> {
+    find_conflicts(conn, &db::list_tasks(conn)?, from, to)
+}
+
+/// the conflicts `task` would cause if it replaced the task called `replaces`, or was added
+pub fn conflicts_with(
+    conn: &Connection,
+    task: &Task,
+    replaces: Option<&str>,
+    now: DateTime<Local>,
+) -> anyhow::Result<Vec<Conflict>> {
+    let mut tasks = db::list_tasks(conn)?;
+    tasks.retain(|t| Some(t.get_name().as_str()) != replaces);
+    tasks.push(task.clone());
+
+    let to = now + chrono::Duration::days(CONFLICT_DAYS);
+    let mut conflicts = find_conflicts(conn, &tasks, now, to)?;
+    conflicts.retain(|c| &c.first == task.get_name() || &c.second == task.get_name());
+    Ok(conflicts)
+}
+
+/// how far ahead to look for conflicts of a new task
+const CONFLICT_DAYS: i64 = 14;
+
+fn find_conflicts(
+    conn: &Connection,
+    tasks: &[Task],
+    from: DateTime<Local>,
+    to: DateTime<Local>,
+) -> anyhow::Result<Vec<Conflict>> {
+    let mut lengths = HashMap::new();
+    let mut outputs = HashMap::new();
+    let mut conflicts = Vec::new();
+    // the end of the queue of each output, and the task playing last on it
+    let mut busy: HashMap<&str, (DateTime<Local>, String)> = HashMap::new();
+
+    for e in expand(conn, tasks, from, to)? {
+        if e.suppressed.is_some() {
+            continue;
+        }
+        let len = match lengths.get(&e.file_name) {
+            Some(len) => *len,
+            None => {
+                let len = db::get_duration(conn, &e.file_name).unwrap_or_else(|err| {
+                    warn!(
+                        "{}: failed to get length of {}\n{err:#?}",
+                        e.task, e.file_name
                     );
+                    Duration::ZERO
+                });
+                let len = chrono::Duration::from_std(len)?;
+                lengths.insert(e.file_name.clone(), len);
+                len
+            }
+        };
 
-                    let sleep_duration = match diff.to_std() {
-                        Ok(d) => d,
-                        Err(_) => {
-                            warn!("{name}: unexpected negative duration, executing immediately");
-                            Duration::ZERO
-                        }
-                    };
-
-                    if select! {
-                        biased;
-                        _ = &mut rx => true,
-                        _ = tokio::time::sleep(sleep_duration) => false,
-                    } {
-                        info!("{name}: cancelled");
-                        m::dec_active_tasks("recurring");
-                        return;
-                    }
-
-                    // record drift
-                    let now = Local::now();
-                    let drift = (now - expected_time).num_milliseconds().abs() as f64 / 1000.0;
-                    m::record_drift("recurring", &name, drift);
-
-                    if let Err(e) = player.play_file(&file_name, priority).await {
-                        error!("{name}: recurring play failed\n{e:#?}");
-                        m::record_playback_failure("recurring", &name);
-                    } else {
-                        debug!("{name}: added to queue, going back to sleep");
-                        m::record_playback_success("recurring", &name);
-                    }
+        // every output has a queue of its own
+        let routes = outputs
+            .entry(e.task.clone())
+            .or_insert_with(|| zones::routes(&e.zones));
+        for route in routes.iter() {
+            let playing = busy.get(route.output).filter(|(end, _)| e.time < *end);
+            // the end of the queue after it, and what plays last
+            let next = match (e.urgency, playing) {
+                (Urgency::Normal, Some((end, first))) => {
+                    conflicts.push(Conflict {
+                        time: e.time,
+                        output: route.output.to_owned(),
+                        first: first.clone(),
+                        second: e.task.clone(),
+                        delay: (*end - e.time).num_milliseconds() as f64 / 1000.0,
+                    });
+                    (*end + len, e.task.clone())
                 }
-            });
+                // urgent plays don't wait in the queue.
+                // one played over another doesn't change when the other ends
+                (Urgency::Duck, Some((end, first))) if *end >= e.time + len => {
+                    (*end, first.clone())
+                }
+                // the rest of the interrupted one is played after it
+                (Urgency::Interrupt { resume: true }, Some((end, _))) => {
+                    (*end + len, e.task.clone())
+                }
+                _ => (e.time + len, e.task.clone()),
+            };
+            busy.insert(route.output, next);
+        }
+    }
+    Ok(conflicts)
+}
+
+/// how far ahead to look for the play of an anchor task
+const ANCHOR_DAYS: i64 = 31;
+
+/// the first play of `task` after `after` that isn't suppressed
+fn next_play(
+    conn: &Connection,
+    task: &Task,
+    after: DateTime<Local>,
+) -> anyhow::Result<Option<DateTime<Local>>> {
+    let to = after + chrono::Duration::days(ANCHOR_DAYS);
+    Ok(expand(conn, std::slice::from_ref(task), after, to)?
+        .into_iter()
+        .find(|e| e.time > after && e.suppressed.is_none())
+        .map(|e| e.time))
+}
+
+/// the time `offset` from now, or from the next play of the task called `anchor`
+/// that is still in the future
+pub fn resolve(
+    conn: &Connection,
+    anchor: Option<String>,
+    offset: chrono::Duration,
+    now: DateTime<Local>,
+) -> anyhow::Result<(DateTime<Local>, Option<Anchor>)> {
+    let Some(name) = anchor else {
+        if offset <= chrono::Duration::zero() {
+            anyhow::bail!("Date is in the past")
         }
+        return Ok((now + offset, None));
+    };
+
+    let task = db::get_task(conn, &name).map_err(|_| anyhow::anyhow!("Anchor task not found"))?;
+    // keeps it from going in circles
+    if task.get_anchor().is_some() {
+        anyhow::bail!("Can't follow a task that follows another one")
+    }
+    let Some(time) = next_play(conn, &task, now - offset)? else {
+        anyhow::bail!("`{name}` doesn't play in the next {ANCHOR_DAYS} days")
+    };
+    Ok((
+        time + offset,
+        Some(Anchor {
+            task: name,
+            offset: offset.num_seconds(),
+        }),
+    ))
+}
+
+/// resolve the tasks following the task called `old` again, eg. after it was edited.
+/// `new` is its current name. returns the moved tasks, to be rescheduled under the same lock.
+pub fn reanchor(conn: &Connection, now: DateTime<Local>, old: &str, new: &str) -> Vec<Task> {
+    let tasks = match db::list_tasks(conn) {
+        Ok(tasks) => tasks,
+        Err(e) => {
+            warn!("{new}: failed to get the tasks following it\n{e:#?}");
+            return Vec::new();
+        }
+    };
+    let mut moved = Vec::new();
+    for task in tasks {
+        let Task::Scheduled {
+            name,
+            file_name,
+            zones,
+            time,
+            missed,
+            anchor: Some(anchor),
+            gain,
+            fade,
+            urgency,
+        } = task
+        else {
+            continue;
+        };
+        if anchor.task != old {
+            continue;
+        }
+
+        let offset = chrono::Duration::seconds(anchor.offset);
+        let (new_time, anchor) = match resolve(conn, Some(new.to_owned()), offset, now) {
+            Ok(resolved) => resolved,
+            Err(e) => {
+                warn!(
+                    "{name}: failed to follow {new}, staying at {}\n{e:#?}",
+                    time.naive_local()
+                );
+                continue;
+            }
+        };
+        let task = Task::Scheduled {
+            name,
+            file_name,
+            zones,
+            time: new_time,
+            missed,
+            anchor,
+            gain,
+            fade,
+            urgency,
+        };
+        let name = task.get_name();
+        if let Err(e) = db::update_task(conn, name, &task, now) {
+            warn!("{name}: failed to save its new time\n{e:#?}");
+            continue;
+        }
+        info!("{name}: following {new}, at {}", new_time.naive_local());
+        moved.push(task);
+    }
+    moved
+}
+
+/// parse and validate a cron expression, the seconds field is optional
+pub fn parse_cron(expr: &str) -> anyhow::Result<Cron> {
+    Cron::new(expr)
+        .with_seconds_optional()
+        .parse()
+        .map_err(|e| anyhow::anyhow!("Invalid cron expression: {e}"))
+}
+
+/// pause all automatic plays until `until`, `None` resumes them right away.
+/// plays of `now` tasks still go through.
+pub fn pause(
+    conn: &Connection,
+    player: &Player,
+    until: Option<DateTime<Local>>,
+) -> anyhow::Result<()> {
+    if until.is_some_and(|until| until <= player.clock.now()) {
+        anyhow::bail!("The pause has to end in the future");
+    }
+    db::set_paused_until(conn, until)?;
+    m::set_paused(until.is_some());
+    match until {
+        Some(until) => {
+            info!("schedule paused until {}", until.naive_local());
+            spawn_resume(player.clone(), until);
+        }
+        None => info!("schedule resumed"),
     }
     Ok(())
 }
+
+/// end the pause at `until`, unless it was changed meanwhile
+pub fn spawn_resume(player: Player, until: DateTime<Local>) {
+    tokio::task::spawn(async move {
+        // never sent, the pause can only be replaced
+        let (_tx, mut rx) = oneshot::channel();
+        sleep_until(&*player.clock, until, &mut rx).await;
+
+        let conn = player.conn.lock().await;
+        let res = match db::get_paused_until(&conn) {
+            Ok(Some(t)) if t <= player.clock.now() => db::set_paused_until(&conn, None),
+            // resumed or extended since
+            Ok(_) => return,
+            Err(e) => Err(e),
+        };
+        match res {
+            Ok(()) => {
+                info!("schedule resumed, the pause has ended");
+                m::set_paused(false);
+            }
+            Err(e) => error!("failed to end the pause\n{e:#?}"),
+        }
+    });
+}
+
+/// archive a recurring task a while after its last day, unless it was changed meanwhile
+fn spawn_archive(player: Player, name: String, until: NaiveDate) {
+    let Some(time) = local_time(
+        &Local,
+        (until + chrono::Duration::days(ARCHIVE_AFTER_DAYS + 1)).and_time(NaiveTime::MIN),
+    ) else {
+        return;
+    };
+    tokio::task::spawn(async move {
+        // never sent, a new validity spawns a new one of these
+        let (_tx, mut rx) = oneshot::channel();
+        sleep_until(&*player.clock, time, &mut rx).await;
+
+        let conn = player.conn.lock().await;
+        match db::get_task(&conn, &name) {
+            Ok(task) if task.get_valid_until() == Some(until) => {
+                match db::archive_task(&conn, &name) {
+                    Ok(_) => info!("{name}: expired on {until}, archived"),
+                    Err(e) => error!("{name}: failed to archive\n{e:#?}"),
+                }
+            }
+            // edited or deleted since
+            _ => (),
+        }
+    });
+}
+
+/// cancel the running task and schedule it again, eg. to pick up new exceptions.
+/// the caller should hold the db lock.
+pub async fn reschedule(task: Task, player: &Player) -> anyhow::Result<()> {
+    player.cancel(task.get_name()).await?;
+    schedule(task, player.clone()).await
+}
+
+/// the shared loop of recurring and cron tasks.
+/// `next` returns the time until the next play from now, `None` if there are no more.
+async fn spawn_repeating(
+    task: &Task,
+    player: Player,
+    next: impl Fn(&Connection, DateTime<Local>) -> Option<(chrono::Duration, Occurrence)>
+        + Send
+        + 'static,
+) {
+    let task = task.clone();
+    let (task_type, name) = (task.get_type(), task.get_name().to_owned());
+    m::inc_active_tasks(task_type);
+
+    // registered before returning, so the task can be cancelled right away
+    let mut rx = player.create_cancel(name.to_owned()).await;
+
+    tokio::task::spawn(async move {
+        // waking up early, the last play is still ahead
+        let mut last = player.clock.now();
+        loop {
+            let conn = player.conn.lock().await;
+            let next = next(&conn, player.clock.now().max(last));
+            drop(conn);
+            let Some((diff, occurrence)) = next else {
+                info!("{name}: no more plays, stopping");
+                m::dec_active_tasks(task_type);
+                player.delete_cancel(&name).await;
+                return;
+            };
+            let expected_time = occurrence.time;
+            let is_tomorrow = expected_time.date_naive() != player.clock.now().date_naive();
+            debug!(
+                "{name} ({task_type}): {} ({}{})",
+                dur_human(&diff).0,
+                expected_time.time().format("%H:%M"),
+                if is_tomorrow { "+" } else { "" }
+            );
+
+            if sleep_until(&*player.clock, expected_time - PREROLL, &mut rx).await {
+                info!("{name}: cancelled");
+                m::dec_active_tasks(task_type);
+                return;
+            }
+            let now = player.clock.now();
+
+            // decide under the db lock, so an edit can't race with the play
+            let conn = player.conn.lock().await;
+            if is_cancelled(&mut rx) {
+                info!("{name}: cancelled");
+                m::dec_active_tasks(task_type);
+                return;
+            }
+            last = expected_time;
+            if let Err(e) = db::set_last_run(&conn, &name, expected_time) {
+                warn!("{name}: failed to save last run\n{e:#?}");
+            }
+
+            if is_suppressed(&conn, &task, expected_time) {
+                continue;
+            }
+
+            // eg. the system was suspended
+            if is_missed(now, expected_time) && !handle_missed(&task, expected_time, now) {
+                continue;
+            }
+
+            let file_name = occurrence
+                .file_name
+                .as_ref()
+                .unwrap_or(task.get_file_name());
+            if let Err(e) = player.play_file_at(&conn, file_name, &task, expected_time) {
+                error!("{name}: {task_type} play failed\n{e:#?}");
+                m::record_playback_failure(task_type, &name);
+            } else {
+                debug!("{name}: added to queue, going back to sleep");
+                m::record_playback_success(task_type, &name);
+            }
+        }
+    });
+}
+
+/// sleep until the wall clock reaches `time`, returns whether the task was cancelled meanwhile.
+/// wakes up regularly, as the monotonic clock stands still while the system is suspended
+async fn sleep_until(
+    clock: &dyn Clock,
+    time: DateTime<Local>,
+    rx: &mut oneshot::Receiver<()>,
+) -> bool {
+    while let Some(left) = (time - clock.now()).to_std().ok().filter(|d| !d.is_zero()) {
+        if select! {
+            biased;
+            _ = &mut *rx => true,
+            _ = clock.sleep(left.min(WAKE_INTERVAL)) => false,
+        } {
+            return true;
+        }
+    }
+    false
+}
+
+/// whether the task was cancelled, or replaced by a new one with the same name
+fn is_cancelled(rx: &mut oneshot::Receiver<()>) -> bool {
+    !matches!(rx.try_recv(), Err(oneshot::error::TryRecvError::Empty))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::SimulatedClock;
+    use chrono::{FixedOffset, Weekday::*};
+    use std::sync::Arc;
+
+    /// central european time, whatever the timezone of the machine running the tests:
+    /// DST starts on the last sunday of march at 02:00, and ends on the last sunday of october
+    /// at 03:00, eg. 2024-03-31 and 2024-10-27
+    #[derive(Debug, Clone, Copy)]
+    struct Cet;
+    impl Cet {
+        fn offset(hours: i32) -> FixedOffset {
+            FixedOffset::east_opt(hours * 3600).unwrap()
+        }
+        fn last_sunday(year: i32, month: u32) -> NaiveDate {
+            let last = NaiveDate::from_ymd_opt(year, month, 31).unwrap();
+            last - chrono::Duration::days(last.weekday().num_days_from_sunday().into())
+        }
+    }
+    impl TimeZone for Cet {
+        type Offset = FixedOffset;
+
+        fn from_offset(_: &FixedOffset) -> Self {
+            Cet
+        }
+        fn offset_from_local_date(&self, local: &NaiveDate) -> LocalResult<FixedOffset> {
+            self.offset_from_local_datetime(&local.and_time(NaiveTime::MIN))
+        }
+        fn offset_from_local_datetime(&self, local: &NaiveDateTime) -> LocalResult<FixedOffset> {
+            // the offsets that give back the same offset from utc
+            let valid: Vec<_> = [Cet::offset(1), Cet::offset(2)]
+                .into_iter()
+                .filter(|o| {
+                    let utc = *local - chrono::Duration::seconds(o.local_minus_utc().into());
+                    self.offset_from_utc_datetime(&utc) == *o
+                })
+                .collect();
+            match valid[..] {
+                [o] => LocalResult::Single(o),
+                [a, b] => LocalResult::Ambiguous(a, b),
+                _ => LocalResult::None,
+            }
+        }
+        fn offset_from_utc_date(&self, utc: &NaiveDate) -> FixedOffset {
+            self.offset_from_utc_datetime(&utc.and_time(NaiveTime::MIN))
+        }
+        fn offset_from_utc_datetime(&self, utc: &NaiveDateTime) -> FixedOffset {
+            let at_1 = NaiveTime::from_hms_opt(1, 0, 0).unwrap();
+            let summer = Cet::last_sunday(utc.year(), 3).and_time(at_1)
+                ..Cet::last_sunday(utc.year(), 10).and_time(at_1);
+            Cet::offset(if summer.contains(utc) { 2 } else { 1 })
+        }
+    }
+
+    fn naive(s: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M").unwrap()
+    }
+    fn cet(s: &str) -> DateTime<Cet> {
+        local_time(&Cet, naive(s)).unwrap()
+    }
+    /// in the timezone of the machine, for the parts that always run on it
+    fn local(s: &str) -> DateTime<Local> {
+        local_time(&Local, naive(s)).unwrap()
+    }
+
+    fn next(now: DateTime<Cet>, times: &[&str], days: &[Weekday]) -> (chrono::Duration, String) {
+        let times = times
+            .iter()
+            .map(|t| NaiveTime::parse_from_str(t, db::TIMEFMT).unwrap())
+            .collect::<Vec<_>>();
+        let (d, o) = duration_until_next(now, &times, days, &[], None, None).unwrap();
+        (d, o.time.format("%Y-%m-%d %H:%M %:z").to_string())
+    }
+
+    fn all() -> Vec<Weekday> {
+        crate::all_days()
+    }
+
+    /// a player on a silent output, with a short beep in its db
+    async fn test_player(clock: Arc<dyn Clock>) -> Player {
+        let output = &crate::zones::outputs()[0].name;
+        let c = crate::sink::Controller::init(output.clone(), Some("null:".into())).unwrap();
+        let metrics = metrics_exporter_prometheus::PrometheusBuilder::new()
+            .build_recorder()
+            .handle();
+        let p = Player::new(vec![c], db::memory().unwrap(), metrics, clock);
+
+        // 100ms of 16-bit mono silence
+        let samples = 4800u32;
+        let mut wav = Vec::new();
+        wav.extend_from_slice(b"RIFF");
+        wav.extend_from_slice(&(36 + samples * 2).to_le_bytes());
+        wav.extend_from_slice(b"WAVEfmt ");
+        wav.extend_from_slice(&16u32.to_le_bytes());
+        wav.extend_from_slice(&1u16.to_le_bytes()); // PCM
+        wav.extend_from_slice(&1u16.to_le_bytes());
+        wav.extend_from_slice(&48000u32.to_le_bytes());
+        wav.extend_from_slice(&96000u32.to_le_bytes());
+        wav.extend_from_slice(&2u16.to_le_bytes());
+        wav.extend_from_slice(&16u16.to_le_bytes());
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&(samples * 2).to_le_bytes());
+        wav.resize(wav.len() + samples as usize * 2, 0);
+        let file = crate::File {
+            name: "beep.wav".into(),
+            data: wav.into(),
+            gain: 0.0,
+            loudness: None,
+        };
+        db::insert_file(&*p.conn.lock().await, file).unwrap();
+        p
+    }
+
+    #[tokio::test]
+    async fn sleep_follows_the_clock() {
+        let clock = SimulatedClock::new(local("2024-03-31 01:00"));
+        let wake = local("2024-03-31 03:30");
+        let (_tx, mut rx) = oneshot::channel();
+
+        let advance = async {
+            while clock.now() < wake {
+                tokio::task::yield_now().await;
+                clock.advance(chrono::Duration::minutes(1));
+            }
+        };
+        let (cancelled, _) = tokio::join!(sleep_until(&clock, wake, &mut rx), advance);
+        assert!(!cancelled);
+        assert_eq!(clock.now(), wake);
+    }
+
+    /// the plays of a recurring task, as the simulated clock goes through midnight.
+    /// it runs in the machine's zone, so DST is left to the tests in `Cet` above
+    #[tokio::test]
+    async fn recurring_plays_follow_the_clock() {
+        // plays are queued `PREROLL` early, so this wakes them at the right time
+        let clock = Arc::new(SimulatedClock::new(local("2024-05-14 23:00") - PREROLL));
+        let p = test_player(clock.clone()).await;
+        let times = ["23:30", "00:30", "01:30"];
+        let task = Task::Recurring {
+            name: "night".into(),
+            file_name: "beep.wav".into(),
+            zones: Vec::new(),
+            time: times
+                .iter()
+                .map(|t| NaiveTime::parse_from_str(t, db::TIMEFMT).unwrap())
+                .collect(),
+            days: all(),
+            profile: None,
+            missed: Missed::default(),
+            valid_from: None,
+            valid_until: None,
+            gain: 0.0,
+            fade: Default::default(),
+            urgency: Default::default(),
+        };
+        db::insert_task(&*p.conn.lock().await, &task, clock.now()).unwrap();
+        schedule(task, p.clone()).await.unwrap();
+
+        let end = local("2024-05-15 03:00");
+        let mut plays = Vec::new();
+        while clock.now() < end {
+            clock.advance(chrono::Duration::minutes(1));
+            // let the task wake up and queue its track
+            tokio::time::sleep(Duration::from_millis(2)).await;
+            for (_, queue) in p.queue() {
+                plays.extend(queue.into_iter().map(|t| (t.name, t.start)));
+            }
+            p.clear_queue(None).unwrap();
+        }
+
+        let expected: Vec<_> = ["2024-05-14 23:30", "2024-05-15 00:30", "2024-05-15 01:30"]
+            .into_iter()
+            .map(local)
+            .collect();
+        assert_eq!(plays.len(), expected.len(), "{plays:?}");
+        for ((name, start), expected) in plays.into_iter().zip(expected) {
+            assert_eq!(name, "beep.wav");
+            // the queue converts it back from an `Instant`
+            let start = start.unwrap();
+            assert!(
+                (start - expected).num_milliseconds().abs() < 500,
+                "{start} {expected}"
+            );
+        }
+    }
+
+    #[test]
+    fn spring_forward() {
+        let (d, at) = next(cet("2024-03-31 01:30"), &["08:00"], &all());
+        assert_eq!(d, chrono::Duration::minutes(5 * 60 + 30));
+        assert_eq!(at, "2024-03-31 08:00 +02:00");
+    }
+
+    #[test]
+    fn spring_forward_gap_is_shifted() {
+        // 02:30 doesn't exist that night
+        let (d, at) = next(cet("2024-03-31 01:00"), &["02:30"], &all());
+        assert_eq!(d, chrono::Duration::minutes(90));
+        assert_eq!(at, "2024-03-31 03:30 +02:00");
+    }
+
+    #[test]
+    fn fall_back() {
+        let (d, at) = next(cet("2024-10-27 01:30"), &["08:00"], &all());
+        assert_eq!(d, chrono::Duration::minutes(7 * 60 + 30));
+        assert_eq!(at, "2024-10-27 08:00 +01:00");
+    }
+
+    #[test]
+    fn fall_back_ambiguous_plays_once() {
+        // 02:30 happens twice that night
+        let (d, at) = next(cet("2024-10-27 01:30"), &["02:30"], &all());
+        assert_eq!(d, chrono::Duration::hours(1));
+        assert_eq!(at, "2024-10-27 02:30 +02:00");
+
+        let after_first = cet("2024-10-27 02:30") + chrono::Duration::minutes(15);
+        let (d, at) = next(after_first, &["02:30"], &all());
+        assert_eq!(d, chrono::Duration::minutes(24 * 60 + 45));
+        assert_eq!(at, "2024-10-28 02:30 +01:00");
+    }
+
+    #[test]
+    fn midnight_rollover() {
+        // monday night
+        let (d, at) = next(cet("2024-10-14 23:59"), &["00:01"], &[Tue]);
+        assert_eq!(d, chrono::Duration::minutes(2));
+        assert_eq!(at, "2024-10-15 00:01 +02:00");
+    }
+
+    #[test]
+    fn weekend_is_skipped() {
+        // friday afternoon
+        let (d, at) = next(
+            cet("2024-10-18 16:00"),
+            &["08:00", "12:00"],
+            &[Mon, Tue, Wed, Thu, Fri],
+        );
+        assert_eq!(d, chrono::Duration::hours(64));
+        assert_eq!(at, "2024-10-21 08:00 +02:00");
+    }
+
+    #[test]
+    fn weekend_across_fall_back() {
+        // friday afternoon, the clocks go back on sunday
+        let (d, at) = next(cet("2024-10-25 16:00"), &["08:00"], &[Mon]);
+        assert_eq!(d, chrono::Duration::hours(65));
+        assert_eq!(at, "2024-10-28 08:00 +01:00");
+    }
+
+    #[test]
+    fn waits_for_validity() {
+        let now = cet("2024-06-28 16:00");
+        let times = [NaiveTime::from_hms_opt(8, 0, 0).unwrap()];
+        let from = NaiveDate::from_ymd_opt(2024, 7, 1);
+        let (d, o) = duration_until_next(now, &times, &all(), &[], from, None).unwrap();
+        assert_eq!(d, chrono::Duration::hours(64));
+        assert_eq!(o.time, cet("2024-07-01 08:00"));
+    }
+
+    #[test]
+    fn expires() {
+        let times = [NaiveTime::from_hms_opt(8, 0, 0).unwrap()];
+        let until = NaiveDate::from_ymd_opt(2024, 7, 1);
+        let last = duration_until_next(cet("2024-07-01 07:00"), &times, &all(), &[], None, until);
+        assert_eq!(last.unwrap().1.time, cet("2024-07-01 08:00"));
+
+        let after = duration_until_next(cet("2024-07-01 09:00"), &times, &all(), &[], None, until);
+        assert!(after.is_none());
+    }
+}
@@ -1,14 +1,23 @@
-use crate::{db, metrics as m, player::Player, scheduler::schedule, templates, Task};
+use crate::{
+    calendar::SchoolYear,
+    db, metrics as m,
+    player::{OutputStatus, Player, FADE_RANGE, GAIN_RANGE},
+    scheduler::{self, schedule},
+    sink::{self, QueueItem},
+    templates, zones, Fade, Missed, Task, Urgency,
+};
 use axum::{
-    extract::{DefaultBodyLimit, MatchedPath, Path, State},
+    extract::{DefaultBodyLimit, MatchedPath, Path, Query, State},
     http::{header, Request, StatusCode},
     middleware::{self, Next},
     response::{IntoResponse, Response},
-    routing::{any, delete, get, post},
+    routing::{any, delete, get, post, put},
     Json, Router,
 };
+use chrono::{DateTime, Local};
 use rust_embed::RustEmbed;
-use std::{env::var, net::IpAddr, time::Instant};
+use serde::Deserialize;
+use std::{collections::HashMap, env::var, net::IpAddr, time::Instant};
 
 pub type AppState = State<Player>;
 
@@ -27,26 +36,105 @@ pub async fn init(p: Player) -> ! {
                 .route("/status", get(templates::Status::get))
                 .route("/status/sse", get(templates::Status::sse))
                 .route("/status/realtime", get(templates::Status::realtime))
+                .route("/status/pause", post(templates::Status::pause))
+                .route("/status/resume", post(templates::Status::resume))
+                .route(
+                    "/queue",
+                    get(templates::Queue::get).delete(templates::Queue::delete),
+                )
+                .route("/queue/:id", delete(templates::Queue::delete_item))
+                .route("/queue/:id/position", put(templates::Queue::put_position))
+                .route("/skip", post(templates::Queue::skip))
                 .route("/form", get(templates::TaskForm::get))
                 .route("/datepicker", get(templates::DatePicker::get))
                 .route("/task", get(templates::Tasks::get))
                 .route("/task", post(templates::Tasks::post))
-                .route("/task/:id", delete(templates::Tasks::delete))
+                .route(
+                    "/task/:id",
+                    put(templates::Tasks::put).delete(templates::Tasks::delete),
+                )
+                .route("/task/:id/edit", get(templates::Tasks::edit))
+                .route(
+                    "/task/:id/exception",
+                    post(templates::Tasks::post_exception),
+                )
+                .route("/exception/:id", delete(templates::Tasks::delete_exception))
                 .route("/file", get(templates::Files::get))
                 .route(
                     "/file",
                     post(templates::Files::post)
                         .layer(DefaultBodyLimit::max(1024usize.pow(2) * 100)), // 100M
                 )
-                .route("/file/:fname", delete(templates::Files::delete)),
+                .route("/file/:fname", delete(templates::Files::delete))
+                .route("/file/:fname/gain", put(templates::Files::put_gain))
+                .route("/calendar", get(templates::Calendar::get))
+                .route("/calendar", post(templates::Calendar::post))
+                .route("/calendar/:id", delete(templates::Calendar::delete))
+                .route("/profile", get(templates::Profiles::get))
+                .route("/profile", post(templates::Profiles::post))
+                .route(
+                    "/profile/:name",
+                    put(templates::Profiles::put).delete(templates::Profiles::delete),
+                )
+                .route("/override", post(templates::Profiles::post_override))
+                .route(
+                    "/override/:date",
+                    delete(templates::Profiles::delete_override),
+                )
+                .route("/playlist", get(templates::Playlists::get))
+                .route("/playlist", post(templates::Playlists::post))
+                .route("/playlist/:name", delete(templates::Playlists::delete))
+                .route(
+                    "/playlist/:name/item",
+                    post(templates::Playlists::post_item),
+                )
+                .route(
+                    "/playlist/:name/item/:id",
+                    delete(templates::Playlists::delete_item),
+                )
+                .route("/timeline", get(templates::Timeline::get))
+                .route(
+                    "/volume",
+                    get(templates::Volume::get).put(templates::Volume::put),
+                )
+                .route(
+                    "/loudness",
+                    get(templates::LoudnessTarget::get).put(templates::LoudnessTarget::put),
+                )
+                .route(
+                    "/pause",
+                    get(templates::Pause::get)
+                        .post(templates::Pause::post)
+                        .delete(templates::Pause::delete),
+                ),
         )
         .nest(
             "/api",
             Router::new()
                 .route("/stop", any(api_stop))
+                .route("/skip", post(api_skip))
+                .route("/track/pause", post(api_track_pause))
+                .route("/track/resume", post(api_track_resume))
+                .route("/queue", get(api_queue_get).delete(api_queue_delete))
+                .route("/queue/:id", delete(api_queue_item_delete))
+                .route("/queue/:id/position", put(api_queue_position_put))
                 .route("/playtest", post(api_playtest))
                 .route("/export", get(api_export))
                 .route("/import", get(api_import))
+                .route("/task/relative", post(api_task_relative))
+                .route("/calendar/import", post(api_calendar_import))
+                .route("/profile", get(api_profile_get).put(api_profile_put))
+                .route("/pause", get(api_pause_get).put(api_pause_put))
+                .route("/volume", get(api_volume_get).put(api_volume_put))
+                .route("/fade", get(api_fade_get).put(api_fade_put))
+                .route("/zones", get(api_zones))
+                .route("/outputs", get(api_outputs))
+                .route("/health", get(api_health))
+                .route("/output/:name/device", put(api_output_device_put))
+                .route("/devices", get(api_devices))
+                .route("/loudness", get(api_loudness_get).put(api_loudness_put))
+                .route("/timeline", get(api_timeline))
+                .route("/conflicts", get(api_conflicts))
                 .route("/file/:fname", get(api_download)),
         )
         .layer(middleware::from_fn(http_metrics_middleware))
@@ -75,6 +163,69 @@ async fn api_stop(State(p): AppState) -> StatusCode {
     info!("STOP");
     StatusCode::NO_CONTENT
 }
+/// fade out the current track, on `?output=` or on every output
+async fn api_skip(
+    State(p): AppState,
+    Query(q): Query<HashMap<String, String>>,
+) -> Result<StatusCode, Response> {
+    p.skip(q.get("output").map(String::as_str))
+        .map_err(|e| (StatusCode::NOT_FOUND, e.to_string()).into_response())?;
+    info!("skip {:?}", q.get("output"));
+    Ok(StatusCode::NO_CONTENT)
+}
+/// hold the current track where it is, on `?output=` or on every output
+async fn api_track_pause(
+    State(p): AppState,
+    Query(q): Query<HashMap<String, String>>,
+) -> Result<StatusCode, Response> {
+    let paused = p
+        .pause(q.get("output").map(String::as_str))
+        .map_err(|e| (StatusCode::NOT_FOUND, e.to_string()).into_response())?;
+    if !paused {
+        return Err((StatusCode::CONFLICT, "Nothing to pause").into_response());
+    }
+    info!("pause {:?}", q.get("output"));
+    Ok(StatusCode::NO_CONTENT)
+}
+async fn api_track_resume(
+    State(p): AppState,
+    Query(q): Query<HashMap<String, String>>,
+) -> Result<StatusCode, Response> {
+    p.resume(q.get("output").map(String::as_str))
+        .map_err(|e| (StatusCode::NOT_FOUND, e.to_string()).into_response())?;
+    info!("resume {:?}", q.get("output"));
+    Ok(StatusCode::NO_CONTENT)
+}
+async fn api_queue_get(State(p): AppState) -> Json<Vec<QueueItem>> {
+    Json(p.queue().into_iter().flat_map(|(_, q)| q).collect())
+}
+/// empty the queue of `?output=` or of every output, the current tracks play on
+async fn api_queue_delete(
+    State(p): AppState,
+    Query(q): Query<HashMap<String, String>>,
+) -> Result<StatusCode, Response> {
+    p.clear_queue(q.get("output").map(String::as_str))
+        .map_err(|e| (StatusCode::NOT_FOUND, e.to_string()).into_response())?;
+    info!("queue cleared {:?}", q.get("output"));
+    Ok(StatusCode::NO_CONTENT)
+}
+async fn api_queue_item_delete(State(p): AppState, Path(id): Path<u64>) -> StatusCode {
+    match p.remove_queued(id) {
+        true => StatusCode::NO_CONTENT,
+        false => StatusCode::NOT_FOUND,
+    }
+}
+/// the new index in the queue of its output, `0` to play it next
+async fn api_queue_position_put(
+    State(p): AppState,
+    Path(id): Path<u64>,
+    Json(position): Json<usize>,
+) -> StatusCode {
+    match p.reorder_queued(id, position) {
+        true => StatusCode::NO_CONTENT,
+        false => StatusCode::NOT_FOUND,
+    }
+}
 async fn api_playtest(State(p): AppState) -> StatusCode {
     p.playtest();
     StatusCode::NO_CONTENT
@@ -96,7 +247,7 @@ async fn api_import(
             continue;
         };
 
-        db::insert_task(&lock.lock, &task).unwrap();
+        db::insert_task(&lock.lock, &task, p.clock.now()).unwrap();
         schedule(task, p.clone())
             .await
             .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()).into_response())?;
@@ -110,6 +261,286 @@ async fn api_import(
 
     Ok(msg)
 }
+/// a task to play `offset` seconds from now, or from the next play of the task `anchor`
+#[derive(Deserialize)]
+struct RelativeTask {
+    name: String,
+    file_name: String,
+    #[serde(default)]
+    zones: Vec<String>,
+    offset: i64,
+    anchor: Option<String>,
+    #[serde(default)]
+    missed: Missed,
+    /// dB
+    #[serde(default)]
+    gain: f32,
+    #[serde(default)]
+    fade: Fade,
+    #[serde(default)]
+    urgency: Urgency,
+}
+/// returns the task, resolved to a scheduled one
+async fn api_task_relative(
+    State(p): AppState,
+    Json(t): Json<RelativeTask>,
+) -> Result<Json<Task>, Response> {
+    let res = async {
+        let conn = p.conn.lock().await;
+        if t.name.trim().is_empty() {
+            anyhow::bail!("`name` can't be empty")
+        }
+        if t.anchor.as_ref() == Some(&t.name) {
+            anyhow::bail!("A task can't follow itself")
+        }
+        if !GAIN_RANGE.contains(&t.gain) {
+            anyhow::bail!("Invalid value `gain`")
+        }
+        if !FADE_RANGE.contains(&t.fade.fade_in) || !FADE_RANGE.contains(&t.fade.fade_out) {
+            anyhow::bail!("Invalid value `fade`")
+        }
+        zones::check(&t.zones)?;
+        let offset = chrono::Duration::seconds(t.offset);
+        let (time, anchor) = scheduler::resolve(&conn, t.anchor, offset, p.clock.now())?;
+        let task = Task::Scheduled {
+            name: t.name,
+            file_name: t.file_name,
+            zones: t.zones,
+            time,
+            missed: t.missed,
+            anchor,
+            gain: t.gain,
+            fade: t.fade,
+            urgency: t.urgency,
+        };
+        db::insert_task(&conn, &task, p.clock.now()).map_err(db::db_err)?;
+        drop(conn);
+
+        m::record_task_created(task.get_type());
+        schedule(task.clone(), p.clone()).await?;
+        Ok(task)
+    };
+    match res.await {
+        Ok(task) => {
+            info!(
+                "{}: scheduled at {}",
+                task.get_name(),
+                task.time_to_str().unwrap_or_default()
+            );
+            Ok(Json(task))
+        }
+        Err(e) => Err((StatusCode::BAD_REQUEST, e.to_string()).into_response()),
+    }
+}
+async fn api_calendar_import(
+    State(p): AppState,
+    Json(year): Json<SchoolYear>,
+) -> Result<impl IntoResponse, Response> {
+    let mut lock = p.lock().await;
+    let existing = lock.list_calendar()?;
+    let entries = year.into_entries();
+    // checked up front, so nothing is imported from a file with a mistake in it
+    if let Some(e) = entries.iter().find(|e| e.end < e.start) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("{}: `end` can't be before `start`", e.name),
+        )
+            .into_response());
+    }
+    let len = entries.len();
+    let mut n = 0;
+    for entry in entries {
+        if existing
+            .iter()
+            .any(|e| e.kind == entry.kind && e.start == entry.start && e.end == entry.end)
+        {
+            continue;
+        }
+
+        db::insert_calendar_entry(&lock.lock, &entry).map_err(|e| {
+            err_to_reply(
+                e.into(),
+                &entry.name,
+                "Failed to save calendar entry",
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        })?;
+        n += 1;
+    }
+    let msg = format!(
+        "Calendar import complete: {len} total, {n} new, {} skipped",
+        len - n
+    );
+    info!("{msg}");
+
+    Ok(msg)
+}
+async fn api_profile_get(State(p): AppState) -> Result<Json<Option<String>>, Response> {
+    db::get_active_profile(&p.lock().await.lock)
+        .map(Json)
+        .map_err(|e| {
+            err_to_reply(
+                e.into(),
+                "Get profile",
+                "Failed to get active profile",
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        })
+}
+/// `null` deactivates all profiles
+async fn api_profile_put(
+    State(p): AppState,
+    Json(name): Json<Option<String>>,
+) -> Result<StatusCode, Response> {
+    db::set_active_profile(&p.lock().await.lock, name.as_deref())
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()).into_response())?;
+    info!("active profile: {name:?}");
+    Ok(StatusCode::NO_CONTENT)
+}
+async fn api_pause_get(State(p): AppState) -> Result<Json<Option<DateTime<Local>>>, Response> {
+    db::get_paused_until(&p.lock().await.lock)
+        .map(|until| Json(until.filter(|until| *until > p.clock.now())))
+        .map_err(|e| {
+            err_to_reply(
+                e.into(),
+                "Get pause",
+                "Failed to get pause",
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        })
+}
+/// pause all schedules until the given time, `null` resumes them
+async fn api_pause_put(
+    State(p): AppState,
+    Json(until): Json<Option<DateTime<Local>>>,
+) -> Result<StatusCode, Response> {
+    scheduler::pause(&p.lock().await.lock, &p, until)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()).into_response())?;
+    Ok(StatusCode::NO_CONTENT)
+}
+async fn api_zones() -> Json<&'static [zones::Zone]> {
+    Json(zones::get())
+}
+async fn api_outputs(State(p): AppState) -> Json<Vec<OutputStatus>> {
+    Json(p.outputs())
+}
+/// the outputs, `503` if any of their devices isn't working
+async fn api_health(State(p): AppState) -> (StatusCode, Json<Vec<OutputStatus>>) {
+    let outputs = p.outputs();
+    let status = match outputs.iter().all(|o| o.health.ok) {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (status, Json(outputs))
+}
+/// the names of the audio devices an output can be switched to
+async fn api_devices() -> Result<Json<Vec<String>>, Response> {
+    tokio::task::spawn_blocking(sink::devices)
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(|res| res)
+        .map(Json)
+        .map_err(|e| {
+            err_to_reply(
+                e,
+                "List devices",
+                "Failed to list devices",
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        })
+}
+/// the name of the device, `null` for the default one. the current track is cut
+async fn api_output_device_put(
+    State(p): AppState,
+    Path(name): Path<String>,
+    Json(device): Json<Option<String>>,
+) -> Result<StatusCode, Response> {
+    p.set_device(&name, device.clone())
+        .await
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()).into_response())?;
+    info!("output {name}: device {device:?}");
+    Ok(StatusCode::NO_CONTENT)
+}
+async fn api_volume_get(State(p): AppState) -> Json<f32> {
+    Json(p.volume())
+}
+/// between 0 and 1, applies to the current track too
+async fn api_volume_put(
+    State(p): AppState,
+    Json(volume): Json<f32>,
+) -> Result<StatusCode, Response> {
+    p.set_volume(&p.lock().await.lock, volume)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()).into_response())?;
+    info!("volume: {volume}");
+    Ok(StatusCode::NO_CONTENT)
+}
+/// seconds, how long a track fades out when it's stopped
+async fn api_fade_get(State(p): AppState) -> Json<f32> {
+    Json(p.stop_fade())
+}
+async fn api_fade_put(State(p): AppState, Json(secs): Json<f32>) -> Result<StatusCode, Response> {
+    p.set_stop_fade(&p.lock().await.lock, secs)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()).into_response())?;
+    info!("stop fade: {secs}s");
+    Ok(StatusCode::NO_CONTENT)
+}
+/// the target of loudness normalization in LUFS, `null` if it's turned off
+async fn api_loudness_get(State(p): AppState) -> Result<Json<Option<f64>>, Response> {
+    db::get_target_loudness(&p.lock().await.lock)
+        .map(Json)
+        .map_err(|e| {
+            err_to_reply(
+                e,
+                "Get target loudness",
+                "Failed to get target loudness",
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        })
+}
+/// `null` turns normalization off
+async fn api_loudness_put(
+    State(p): AppState,
+    Json(target): Json<Option<f64>>,
+) -> Result<StatusCode, Response> {
+    db::set_target_loudness(&p.lock().await.lock, target)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()).into_response())?;
+    info!("target loudness: {target:?} LUFS");
+    Ok(StatusCode::NO_CONTENT)
+}
+async fn api_timeline(
+    State(p): AppState,
+    Query(q): Query<HashMap<String, String>>,
+) -> Result<Json<Vec<scheduler::Event>>, Response> {
+    let (from, to) = templates::query_range(&q, 1, p.clock.now())
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()).into_response())?;
+    scheduler::timeline(&p.lock().await.lock, from, to)
+        .map(Json)
+        .map_err(|e| {
+            err_to_reply(
+                e,
+                "Timeline",
+                "Failed to get timeline",
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        })
+}
+async fn api_conflicts(
+    State(p): AppState,
+    Query(q): Query<HashMap<String, String>>,
+) -> Result<Json<Vec<scheduler::Conflict>>, Response> {
+    let (from, to) = templates::query_range(&q, 7, p.clock.now())
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()).into_response())?;
+    scheduler::conflicts(&p.lock().await.lock, from, to)
+        .map(Json)
+        .map_err(|e| {
+            err_to_reply(
+                e,
+                "Conflicts",
+                "Failed to get conflicts",
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        })
+}
 async fn api_download(
     State(p): AppState,
     Path(fname): Path<String>,
@@ -1,25 +1,252 @@
 // low-level audio sink implementation
 // for a higher-level interface, see `src/player.rs`
-use crate::{metrics as m, player::NowPlaying};
+use crate::{
+    backend::{Backend, Headless},
+    mail, metrics as m,
+    player::{NowPlaying, Position},
+    Urgency,
+};
+use chrono::{DateTime, Local};
 use rodio::{
+    cpal::{self, traits::HostTrait},
     source::{Empty, Zero},
-    OutputStream, Source,
+    DeviceTrait, OutputStream, Source,
 };
+use serde::Serialize;
 use std::{
     collections::VecDeque,
     sync::{
-        atomic::{AtomicBool, Ordering},
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        mpsc::{self, RecvTimeoutError},
         Arc, Mutex,
     },
+    thread,
     time::{Duration, Instant},
 };
-use tokio::sync::watch::{self, Receiver, Sender};
+use tokio::{
+    runtime::Handle,
+    sync::watch::{self, Receiver, Sender},
+};
+
+/// the longest chunk of silence played while idle
+const SILENCE: Duration = Duration::from_millis(500);
+/// how often to check that the device is still taking samples
+const WATCHDOG: Duration = Duration::from_secs(2);
+/// the wait before reopening a lost device, doubled after each failure
+const RETRY_MIN: Duration = Duration::from_secs(1);
+const RETRY_MAX: Duration = Duration::from_secs(60);
+/// how much the track under a ducking one is turned down, about -14 dB
+const DUCK_LEVEL: f32 = 0.2;
+/// how often the queue is checked for urgent tracks, in samples
+const URGENT_CHECK: u32 = 1024;
+
+/// seconds, how long a track fades when it's paused or resumed, so it doesn't click
+const PAUSE_FADE: f32 = 0.02;
+
+/// the id of the next track queued, unique across the outputs
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
 
-struct Output {
+/// the queue as a single endless source, played by the backend
+pub struct Output {
     controller: Controller,
     track: Track,
-    np_tx: Sender<Option<NowPlaying>>,
+    /// an urgent track played over the current one, which is ducked meanwhile
+    overlay: Option<Track>,
+    /// the track that continues after the current one, if it was interrupted
+    resuming: Option<String>,
     current_track_started: Option<Instant>, // for metrics
+    /// the samples of the current track played so far
+    samples: u64,
+    /// the samples since the queue was last checked for urgent tracks
+    since_check: u32,
+    /// the number of tracks started, including silence
+    switches: u64,
+    /// the number of cuts handled, see [`Controls::cuts`]
+    cuts: u64,
+    /// the samples faded out so far, and the length of the fade, while the track is being cut
+    fade_out: Option<(u64, u64)>,
+    /// put the track back in the queue once it's faded out
+    resume: bool,
+    /// the level of the current track while it's being paused or resumed, between 0 and 1
+    pause_level: f32,
+    /// the current track is paused, it's kept where it is and silence is played instead
+    held: bool,
+}
+impl Output {
+    fn new(controller: Controller) -> Self {
+        Output {
+            cuts: controller.controls.cuts.load(Ordering::Relaxed),
+            controller,
+            track: Track {
+                id: 0,
+                src: Box::new(Empty::new()),
+                name: None,
+                start: None,
+                task: None,
+                playlist: None,
+                urgency: Urgency::Normal,
+                played: Duration::ZERO,
+            },
+            overlay: None,
+            resuming: None,
+            current_track_started: None,
+            samples: 0,
+            since_check: 0,
+            switches: 0,
+            fade_out: None,
+            resume: false,
+            pause_level: 1.0,
+            held: false,
+        }
+    }
+
+    pub fn switches(&self) -> u64 {
+        self.switches
+    }
+    /// `None` while it's silent
+    pub fn track_name(&self) -> Option<&str> {
+        self.track.name.as_deref()
+    }
+
+    /// start fading out the current track, it ends when it's silent
+    fn cut(&mut self) {
+        if self.track.name.is_none() || self.fade_out.is_some() {
+            return;
+        }
+        let rate = self.track.src.sample_rate() as f32 * self.track.src.channels() as f32;
+        let len = self.controller.stop_fade() * rate;
+        self.fade_out = Some((0, len as u64));
+    }
+    /// the cut track has faded out
+    fn end_cut(&mut self) {
+        self.fade_out = None;
+        self.held = false;
+        self.pause_level = 1.0;
+        self.overlay = None;
+        let rate = self.track.src.sample_rate() as f64 * self.track.src.channels() as f64;
+        let src = std::mem::replace(&mut self.track.src, Box::new(Empty::new()));
+        if !std::mem::take(&mut self.resume) {
+            return;
+        }
+
+        // back in the queue right after the urgent tracks and the planned ones that are due,
+        // fading in where it was cut
+        let played = self.track.played + Duration::from_secs_f64(self.samples as f64 / rate);
+        let track = Track {
+            id: self.track.id,
+            src: Box::new(src.fade_in(Duration::from_secs_f32(self.controller.stop_fade()))),
+            name: self.track.name.clone(),
+            start: None,
+            task: None,
+            playlist: self.track.playlist.clone(),
+            urgency: Urgency::Normal,
+            played,
+        };
+        let mut q = self.controller.q.lock().unwrap();
+        let now = Instant::now();
+        let i = q
+            .iter()
+            .position(|t| !t.urgency.is_urgent() && t.start.is_none_or(|s| s > now))
+            .unwrap_or(q.len());
+        q.insert(i, track);
+        m::set_queue_size(q.len());
+    }
+
+    /// interrupt or duck the current track if an urgent one is due,
+    /// or put a paused one back in the queue if any planned track is due, so a pause can't block it
+    fn check_urgent(&mut self) {
+        // never block the audio thread, it's checked again soon
+        let Ok(mut q) = self.controller.q.try_lock() else {
+            return;
+        };
+        let now = Instant::now();
+        let held = self.held;
+        let Some(urgent) = q.front().filter(|t| {
+            (t.urgency.is_urgent() || (held && t.start.is_some()))
+                && t.start.is_none_or(|s| s <= now)
+        }) else {
+            return;
+        };
+
+        if self.track.name.is_none() {
+            // no need to wait for the silence to end
+            self.track.src = Box::new(Empty::new());
+            return;
+        }
+        // mixing needs the same format, which tracks on the same output have
+        let fits = urgent.src.channels() == self.track.src.channels()
+            && urgent.src.sample_rate() == self.track.src.sample_rate();
+        match urgent.urgency {
+            Urgency::Duck if fits && !self.held => {
+                let over = q.pop_front().unwrap();
+                m::set_queue_size(q.len());
+                drop(q);
+                info!("playing over {:?}: {:?}", self.track.name, over.name);
+                record_drift(&over, now);
+                self.overlay = Some(over);
+                self.update_np();
+            }
+            Urgency::Normal => {
+                let due = urgent.name.clone();
+                drop(q);
+                warn!(
+                    "{}: {:?} is due, putting paused {:?} back in the queue",
+                    self.controller.name, due, self.track.name
+                );
+                m::record_pause_overridden(&self.controller.name);
+                self.resume = true;
+                self.cut();
+            }
+            Urgency::Interrupt { resume } => {
+                drop(q);
+                info!("interrupting {:?}", self.track.name);
+                self.resume = resume;
+                self.cut();
+            }
+            _ => {
+                drop(q);
+                info!("interrupting {:?}, it can't be ducked", self.track.name);
+                // a paused track is silent already, it can wait for the urgent one
+                self.resume = self.held;
+                self.cut();
+            }
+        }
+    }
+
+    /// how far the current track is, including what was played before it was interrupted
+    fn position(&self) -> Duration {
+        let rate = self.track.src.sample_rate() as f64 * self.track.src.channels() as f64;
+        self.track.played + Duration::from_secs_f64(self.samples as f64 / rate)
+    }
+    fn now_playing(&self) -> Option<NowPlaying> {
+        let base = self.track.name.as_ref()?;
+        let (track, position, under) = match &self.overlay {
+            Some(over) => (over, over.played, Some(base.clone())),
+            None => (&self.track, self.position(), self.resuming.clone()),
+        };
+        let now = Instant::now();
+        Some(NowPlaying {
+            name: track.name.clone().unwrap_or_default(),
+            len: track.src.total_duration(),
+            started: now.checked_sub(position).unwrap_or(now),
+            paused: self.held.then_some(position),
+            playlist: track.playlist.clone(),
+            urgency: track.urgency,
+            under,
+        })
+    }
+    /// signal the start of a track and update the playback_active metric
+    fn update_np(&self) {
+        let np = self.now_playing();
+        self.controller.np_tx.send_if_modified(|prev| {
+            if prev.is_none() && np.is_none() {
+                return false;
+            }
+            m::set_playback_active(np.is_some());
+            *prev = np;
+            true
+        });
+    }
 }
 impl Source for Output {
     // should never return `None` or `0`
@@ -59,11 +286,108 @@ impl Iterator for Output {
     type Item = f32;
 
     fn next(&mut self) -> Option<Self::Item> {
+        let controls = &self.controller.controls;
+        // for the watchdog
+        controls.pulled.fetch_add(1, Ordering::Relaxed);
+
+        // checked on every sample, so a stop is heard within a buffer
+        let cuts = controls.cuts.load(Ordering::Relaxed);
+        if cuts != self.cuts {
+            self.cuts = cuts;
+            self.resume = false;
+            self.cut();
+        }
+
+        // urgent tracks don't preempt each other
+        if self.fade_out.is_none() && self.overlay.is_none() && !self.track.urgency.is_urgent() {
+            self.since_check += 1;
+            // ducking starts on a frame boundary, so the channels line up
+            if self.since_check >= URGENT_CHECK
+                && self
+                    .samples
+                    .is_multiple_of(self.track.src.channels() as u64)
+            {
+                self.since_check = 0;
+                self.check_urgent();
+            }
+        }
+        // a paused track is silent already, no need to fade it out
+        if self.held && self.fade_out.is_some() {
+            self.end_cut();
+            self.controller
+                .controls
+                .paused
+                .store(false, Ordering::Relaxed);
+        }
+        let controls = &self.controller.controls;
+
+        // only a normal track can be paused, not silence or an urgent one
+        let mut paused = controls.paused.load(Ordering::Relaxed);
+        if paused
+            && (self.track.name.is_none()
+                || self.track.urgency.is_urgent()
+                || self.overlay.is_some())
+        {
+            controls.paused.store(false, Ordering::Relaxed);
+            paused = false;
+        }
+        if paused || self.pause_level < 1.0 {
+            let rate = self.track.src.sample_rate() as f32 * self.track.src.channels() as f32;
+            let step = 1.0 / (PAUSE_FADE * rate);
+            self.pause_level = match paused {
+                true => (self.pause_level - step).max(0.0),
+                false => (self.pause_level + step).min(1.0),
+            };
+        }
+        // faded out, hold it on a frame boundary, so the channels line up when it's resumed
+        if paused
+            && self.pause_level == 0.0
+            && self.fade_out.is_none()
+            && self
+                .samples
+                .is_multiple_of(self.track.src.channels() as u64)
+        {
+            if !self.held {
+                self.held = true;
+                info!("paused: {:?}", self.track.name);
+                self.update_np();
+            }
+            return Some(0.0);
+        }
+        if self.held {
+            self.held = false;
+            info!("resumed: {:?}", self.track.name);
+            self.update_np();
+        }
+
         loop {
             // keep playing current track
-            if let Some(sample) = self.track.src.next() {
-                return Some(sample);
+            if let Some(mut sample) = self.track.src.next() {
+                self.samples += 1;
+                if let Some(over) = &mut self.overlay {
+                    match over.src.next() {
+                        Some(s) => sample = sample * DUCK_LEVEL + s,
+                        None => {
+                            // back up
+                            self.overlay = None;
+                            self.update_np();
+                        }
+                    }
+                }
+
+                let mut level = self.controller.volume();
+                if let Some((done, len)) = &mut self.fade_out {
+                    if *done >= *len {
+                        // faded out, on to the next one
+                        self.end_cut();
+                        continue;
+                    }
+                    level *= 1.0 - *done as f32 / *len as f32;
+                    *done += 1;
+                }
+                return Some(sample * level * self.pause_level);
             }
+            self.fade_out = None;
 
             // track ended - record playback duration if it was a named track
             if let (Some(started), Some(name)) =
@@ -73,123 +397,473 @@ impl Iterator for Output {
                 m::record_playback_seconds(name, elapsed);
                 debug!("end of track: {:?} (played {:.2}s)", name, elapsed);
             }
+            self.samples = 0;
+            self.since_check = 0;
+            self.resuming = None;
+            // a pause only holds the track it was meant for
+            self.pause_level = 1.0;
+            self.controller
+                .controls
+                .paused
+                .store(false, Ordering::Relaxed);
 
-            // get next track
-            let mut q = self.controller.q.lock().unwrap();
-            if let Some(next) = q.pop_front() {
-                // update queue size metric
-                m::set_queue_size(q.len());
-
-                self.track = next;
-                if let Some(name) = &self.track.name {
-                    info!("playing: {:?}", name);
-                    self.current_track_started = Some(Instant::now());
-                }
+            let now = Instant::now();
+            if let Some(over) = self.overlay.take() {
+                // the ducked track ended first, the one over it goes on alone
+                self.track = over;
+                self.current_track_started = Some(now);
             } else {
-                // queue is empty - update metrics
-                m::set_queue_size(0);
-
-                // play a bit of silence
-                self.track = Track {
-                    // this will give every play a worst-case 500ms delay, but in the context of this program and the benefits of lower resource usage, that's acceptable
-                    src: Box::new(Zero::new(1, 44100).take_duration(Duration::from_millis(500))),
-                    name: None,
+                // get next track
+                let mut q = self.controller.q.lock().unwrap();
+                // the time left until the next track is due
+                let wait = q
+                    .front()
+                    .and_then(|t| t.start)
+                    .map(|start| start.saturating_duration_since(now))
+                    .filter(|d| !d.is_zero());
+                let next = match wait {
+                    None => q.pop_front(),
+                    Some(_) => None,
                 };
-            }
+                // update queue size metric
+                m::set_queue_size(q.len());
 
-            // signal start of track and update playback_active metric
-            self.np_tx
-                .send_if_modified(|prev| match (&prev, self.track.name.to_owned()) {
-                    (None, None) => false,
-                    (Some(_), None) => {
-                        m::set_playback_active(false);
-                        *prev = None;
-                        true
+                if let Some(next) = next {
+                    self.track = next;
+                    if let Some(name) = &self.track.name {
+                        info!("playing: {:?}", name);
+                        self.current_track_started = Some(now);
                     }
-                    (_, Some(name)) => {
-                        m::set_playback_active(true);
-                        *prev = Some(NowPlaying {
-                            name,
-                            len: self.track.src.total_duration(),
-                            started: Instant::now(),
-                        });
-                        true
+                    record_drift(&self.track, now);
+                    // an interrupted track waiting for this one
+                    if self.track.urgency.is_urgent() {
+                        self.resuming = q
+                            .iter()
+                            .find(|t| !t.urgency.is_urgent())
+                            .filter(|t| !t.played.is_zero())
+                            .and_then(|t| t.name.clone());
                     }
-                });
+                } else {
+                    // play a bit of silence
+                    self.track = Track {
+                        id: 0,
+                        // this will give every unplanned play a worst-case 500ms delay, but in the context of this program and the benefits of lower resource usage, that's acceptable.
+                        // planned tracks are queued early, and the silence is cut short to start them on time
+                        src: Box::new(
+                            Zero::new(1, 44100).take_duration(wait.unwrap_or(SILENCE).min(SILENCE)),
+                        ),
+                        name: None,
+                        start: None,
+                        task: None,
+                        playlist: None,
+                        urgency: Urgency::Normal,
+                        played: Duration::ZERO,
+                    };
+                }
+            }
+            self.switches += 1;
+            self.update_np();
         }
     }
 }
 
+/// how close a track got to its planned start, negative if it started early
+fn record_drift(track: &Track, now: Instant) {
+    if let (Some(start), Some((task_type, task_name))) = (track.start, &track.task) {
+        let offset = match now.checked_duration_since(start) {
+            Some(late) => late.as_secs_f64(),
+            None => -start.duration_since(now).as_secs_f64(),
+        };
+        m::record_drift(task_type, task_name, offset);
+    }
+}
+
+/// whatever plays the output, it's only kept to be dropped when the output is switched
+type Stream = Box<dyn std::any::Any>;
+
+/// a new device for the output, and where to send the result of opening it
+type DeviceRequest = (Option<String>, mpsc::Sender<anyhow::Result<()>>);
+
+/// the queue of an output
 #[derive(Clone)]
 pub struct Controller {
+    /// the name of the output
+    pub name: String,
     pub q: Arc<Mutex<VecDeque<Track>>>,
     controls: Arc<Controls>,
+    np_tx: Arc<Sender<Option<NowPlaying>>>,
+    device_tx: mpsc::Sender<DeviceRequest>,
 }
 impl Controller {
-    pub fn init() -> (Controller, Receiver<Option<NowPlaying>>) {
-        let (_stream, _handle) = OutputStream::try_default().unwrap_or_else(|e| {
-            m::record_audio_error();
-            panic!("failed to find output device: {e}");
-        });
-        let (np_tx, np_rx) = watch::channel(None);
+    /// start playing on `device`, the default one if `None`.
+    /// if it can't be opened, it's retried in the background, and the queue waits for it
+    pub fn init(name: String, device: Option<String>) -> anyhow::Result<Controller> {
+        let (np_tx, _) = watch::channel(None);
+        let (device_tx, device_rx) = mpsc::channel();
         let controller = Controller {
+            name,
             q: Arc::new(Mutex::new(VecDeque::new())),
             controls: Arc::new(Controls {
-                stop: AtomicBool::new(false),
+                cuts: AtomicU64::new(0),
+                volume: AtomicU32::new(1f32.to_bits()),
+                stop_fade: AtomicU32::new(0f32.to_bits()),
+                device: Mutex::new(device.clone()),
+                pulled: AtomicU64::new(0),
+                paused: AtomicBool::new(false),
+                health: Mutex::new(Health {
+                    ok: true,
+                    error: None,
+                    since: Local::now(),
+                }),
             }),
+            np_tx: Arc::new(np_tx),
+            device_tx,
         };
-        let output = Output {
-            controller: controller.clone(),
-            track: Track {
-                src: Box::new(Empty::new()),
-                name: None,
-            },
-            np_tx,
-            current_track_started: None,
+
+        // the stream can't be moved between threads, so it gets one of its own.
+        // this also keeps blocking functions off the tokio threads
+        m::set_output_up(&controller.name, true);
+        let (ready_tx, ready_rx) = mpsc::channel();
+        let c = controller.clone();
+        let rt = Handle::current();
+        thread::Builder::new()
+            .name(format!("output-{}", controller.name))
+            .spawn(move || c.run(rt, device, ready_tx, device_rx))?;
+        // wait for the first try, so the health is known
+        let _ = ready_rx.recv();
+
+        Ok(controller)
+    }
+
+    /// owns the stream, replaces it when the device changes,
+    /// and reopens it with backoff if it's lost
+    fn run(
+        self,
+        rt: Handle,
+        mut device: Option<String>,
+        ready_tx: mpsc::Sender<()>,
+        device_rx: mpsc::Receiver<DeviceRequest>,
+    ) {
+        let mut stream = self.reopen(&rt, &device);
+        let _ = ready_tx.send(());
+        let mut retry = RETRY_MIN;
+        let mut pulled = self.controls.pulled.load(Ordering::Relaxed);
+
+        loop {
+            let timeout = match stream {
+                Some(_) => WATCHDOG,
+                None => retry,
+            };
+            match device_rx.recv_timeout(timeout) {
+                Ok((new, res_tx)) => {
+                    let res = self.open(new.clone()).map(|new_stream| {
+                        // the old one stops when it's dropped, along with its current track
+                        stream = Some(new_stream);
+                        device = new;
+                        retry = RETRY_MIN;
+                        self.set_health(&rt, None);
+                    });
+                    let _ = res_tx.send(res);
+                }
+                Err(RecvTimeoutError::Timeout) if stream.is_some() => {
+                    // the callback isn't called anymore, eg. the device was unplugged
+                    if self.controls.pulled.load(Ordering::Relaxed) == pulled {
+                        m::record_audio_error();
+                        stream = None;
+                        self.set_health(&rt, Some("The device stopped playing".into()));
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    stream = self.reopen(&rt, &device);
+                    retry = match stream {
+                        Some(_) => RETRY_MIN,
+                        None => (retry * 2).min(RETRY_MAX),
+                    };
+                }
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+            pulled = self.controls.pulled.load(Ordering::Relaxed);
+        }
+    }
+
+    /// open the device, and record whether it worked
+    fn reopen(&self, rt: &Handle, device: &Option<String>) -> Option<Stream> {
+        match self.open(device.clone()) {
+            Ok(stream) => {
+                self.set_health(rt, None);
+                Some(stream)
+            }
+            Err(e) => {
+                self.set_health(rt, Some(e.to_string()));
+                None
+            }
+        }
+    }
+
+    fn open(&self, device: Option<String>) -> anyhow::Result<Stream> {
+        let stream: Stream = match Backend::parse(device.as_deref()) {
+            Backend::Device(name) => {
+                let res = match &name {
+                    None => OutputStream::try_default().map_err(anyhow::Error::from),
+                    Some(name) => find_device(name).and_then(|d| {
+                        OutputStream::try_from_device(&d).map_err(anyhow::Error::from)
+                    }),
+                };
+                let (stream, handle) = res.inspect_err(|_| m::record_audio_error())?;
+                handle.play_raw(Output::new(self.clone()))?;
+                Box::new(stream)
+            }
+            Backend::Null => Box::new(Headless::start(
+                &self.name,
+                Output::new(self.clone()),
+                None,
+            )?),
+            Backend::Wav(dir) => Box::new(Headless::start(
+                &self.name,
+                Output::new(self.clone()),
+                Some(dir),
+            )?),
         };
 
-        // exit the tokio async thread to be able to use blocking functions
-        tokio::task::spawn_blocking(move || {
-            _handle.play_raw(output).unwrap();
-            Box::leak(Box::new(_handle));
-        });
+        info!(
+            "{}: playing on {}",
+            self.name,
+            device.as_deref().unwrap_or("the default device")
+        );
+        *self.controls.device.lock().unwrap() = device;
+        Ok(stream)
+    }
+
+    /// `error` is `None` if the device works. a notification is sent when it changes
+    fn set_health(&self, rt: &Handle, error: Option<String>) {
+        let mut health = self.controls.health.lock().unwrap();
+        let ok = error.is_none();
+        if let Some(e) = &error {
+            warn!("{}: the output device isn't working: {e}", self.name);
+        }
+        if health.ok == ok {
+            health.error = error;
+            return;
+        }
+
+        *health = Health {
+            ok,
+            error,
+            since: Local::now(),
+        };
+        m::set_output_up(&self.name, ok);
+        // the stream was replaced, so nothing's playing
+        self.np_tx.send_replace(None);
+        if ok {
+            info!("{}: the output device is back", self.name);
+            rt.spawn(mail::output_restored(self.name.clone()));
+        } else {
+            let error = health.error.clone().unwrap_or_default();
+            rt.spawn(mail::output_lost(self.name.clone(), error));
+        }
+    }
+    pub fn health(&self) -> Health {
+        self.controls.health.lock().unwrap().clone()
+    }
 
-        Box::leak(Box::new(_stream));
+    /// the name of the device, `None` for the default one
+    pub fn device(&self) -> Option<String> {
+        self.controls.device.lock().unwrap().clone()
+    }
+    /// switch to another device, blocks until it's opened.
+    /// the current track is cut, the queue is kept
+    pub fn set_device(&self, device: Option<String>) -> anyhow::Result<()> {
+        let (res_tx, res_rx) = mpsc::channel();
+        self.device_tx
+            .send((device, res_tx))
+            .map_err(|_| anyhow::anyhow!("The output isn't running"))?;
+        res_rx
+            .recv()
+            .map_err(|_| anyhow::anyhow!("The output isn't running"))?
+    }
 
-        (controller, np_rx)
+    pub fn np_rx(&self) -> Receiver<Option<NowPlaying>> {
+        self.np_tx.subscribe()
     }
 
+    /// urgent tracks go before the rest, and preempt the one playing when they're due
     pub fn append(&self, mut t: Track) {
-        // resume playback
-        self.controls.stop.store(false, Ordering::Relaxed);
-
-        let c = self.controls.clone();
-        t.src = Box::new(t.src.stoppable().periodic_access(
-            Duration::from_millis(420),
-            move |src| {
-                if c.stop.load(Ordering::Relaxed) {
-                    src.stop();
-                }
-            },
-        ));
+        t.id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let mut q = self.q.lock().unwrap();
+        match t.urgency.is_urgent() {
+            true => {
+                let i = q
+                    .iter()
+                    .position(|t| !t.urgency.is_urgent())
+                    .unwrap_or(q.len());
+                q.insert(i, t);
+            }
+            false => q.push_back(t),
+        }
+        m::set_queue_size(q.len());
+    }
+
+    /// the master volume, between 0 and 1
+    pub fn volume(&self) -> f32 {
+        f32::from_bits(self.controls.volume.load(Ordering::Relaxed))
+    }
+    /// applies to the current track too
+    pub fn set_volume(&self, volume: f32) {
+        self.controls
+            .volume
+            .store(volume.clamp(0.0, 1.0).to_bits(), Ordering::Relaxed);
+    }
 
+    /// seconds, how long the current track fades out when it's cut
+    pub fn stop_fade(&self) -> f32 {
+        f32::from_bits(self.controls.stop_fade.load(Ordering::Relaxed))
+    }
+    pub fn set_stop_fade(&self, secs: f32) {
+        self.controls
+            .stop_fade
+            .store(secs.max(0.0).to_bits(), Ordering::Relaxed);
+    }
+
+    /// the tracks waiting in the queue, in order. `now` is the time on the player's clock
+    pub fn queue(&self, now: DateTime<Local>) -> Vec<QueueItem> {
+        let now = (Instant::now(), now);
+        self.q
+            .lock()
+            .unwrap()
+            .iter()
+            .enumerate()
+            .map(|(position, t)| QueueItem {
+                id: t.id,
+                output: self.name.clone(),
+                position,
+                name: t.name.clone().unwrap_or_default(),
+                len: t.src.total_duration().map(|d| d.as_secs_f64()),
+                start: t.start.map(|start| {
+                    now.1
+                        + chrono::Duration::from_std(start.saturating_duration_since(now.0))
+                            .unwrap_or_else(|_| chrono::Duration::zero())
+                }),
+                urgency: t.urgency,
+            })
+            .collect()
+    }
+    /// returns false if it's not in the queue
+    pub fn remove(&self, id: u64) -> bool {
         let mut q = self.q.lock().unwrap();
-        q.push_back(t);
+        let Some(i) = q.iter().position(|t| t.id == id) else {
+            return false;
+        };
+        q.remove(i);
         m::set_queue_size(q.len());
+        true
+    }
+    /// move a track to `position` in the queue, or as close to it as it can go:
+    /// urgent tracks stay before the rest, like in [`Controller::append`]
+    pub fn reorder(&self, id: u64, position: usize) -> bool {
+        let mut q = self.q.lock().unwrap();
+        let Some(track) = q.iter().position(|t| t.id == id).and_then(|i| q.remove(i)) else {
+            return false;
+        };
+        let urgent = q
+            .iter()
+            .position(|t| !t.urgency.is_urgent())
+            .unwrap_or(q.len());
+        let position = match track.urgency.is_urgent() {
+            true => position.min(urgent),
+            false => position.clamp(urgent, q.len()),
+        };
+        q.insert(position, track);
+        true
     }
 
-    pub fn stop(&self) {
+    /// hold the current track where it is, the ones after it wait for it unless they're due
+    pub fn pause(&self) {
+        self.controls.paused.store(true, Ordering::Relaxed);
+    }
+    /// continue the paused track from where it was
+    pub fn resume(&self) {
+        self.controls.paused.store(false, Ordering::Relaxed);
+    }
+    /// fade out the current track, the queue goes on
+    pub fn skip(&self) {
+        self.controls.paused.store(false, Ordering::Relaxed);
+        self.controls.cuts.fetch_add(1, Ordering::Relaxed);
+    }
+    /// empty the queue, the current track plays on
+    pub fn clear(&self) {
         self.q.lock().unwrap().clear();
-        self.controls.stop.store(true, Ordering::Relaxed);
         m::set_queue_size(0);
     }
+    /// clear the queue, and fade out the current track
+    pub fn stop(&self) {
+        self.clear();
+        self.skip();
+    }
+}
+
+/// a track waiting in the queue of an output
+#[derive(Debug, Clone, Serialize)]
+pub struct QueueItem {
+    pub id: u64,
+    pub output: String,
+    /// its index in the queue of the output
+    pub position: usize,
+    pub name: String,
+    /// seconds, `None` if it's unknown
+    pub len: Option<f64>,
+    /// when it's planned to start, `None` if it's as soon as possible
+    pub start: Option<DateTime<Local>>,
+    pub urgency: Urgency,
 }
 
 struct Controls {
-    stop: AtomicBool, // atomic for interior mutability
+    /// the number of times the current track was cut, eg. stopped
+    cuts: AtomicU64,
+    volume: AtomicU32,    // the bits of an f32
+    stop_fade: AtomicU32, // seconds, the bits of an f32
+    device: Mutex<Option<String>>,
+    /// the number of samples taken by the device
+    pulled: AtomicU64,
+    /// hold the current track where it is, until it's resumed
+    paused: AtomicBool,
+    health: Mutex<Health>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Health {
+    /// whether the device can be played on
+    pub ok: bool,
+    /// why not
+    pub error: Option<String>,
+    /// when it last changed
+    pub since: DateTime<Local>,
+}
+
+/// the names of the audio devices that can be played on
+pub fn devices() -> anyhow::Result<Vec<String>> {
+    Ok(cpal::default_host()
+        .output_devices()?
+        .filter_map(|d| d.name().ok())
+        .collect())
+}
+fn find_device(name: &str) -> anyhow::Result<cpal::Device> {
+    cpal::default_host()
+        .output_devices()?
+        .find(|d| d.name().is_ok_and(|n| n == name))
+        .ok_or_else(|| anyhow::anyhow!("Output device not found: {name}"))
 }
 
 pub struct Track {
+    /// unique, set when it's queued
+    pub id: u64,
     pub name: Option<String>,
     pub src: Box<dyn Source<Item = f32> + Send + Sync>,
+    /// don't start before this
+    pub start: Option<Instant>,
+    /// the type and name of the task playing it, for metrics
+    pub task: Option<(&'static str, String)>,
+    pub playlist: Option<Position>,
+    pub urgency: Urgency,
+    /// how much of it was played before it was interrupted
+    pub played: Duration,
 }
@@ -1,9 +1,12 @@
 use crate::{
+    all_days,
+    calendar::{self, Entry},
     db, metrics as m,
-    player::{NowPlaying, Player},
-    scheduler::schedule,
+    player::{measure, NowPlaying, Player, PlayerLock, FADE_RANGE, GAIN_RANGE},
+    scheduler::{self, parse_cron, reschedule, schedule, ConflictRule},
     server::{err_to_reply, AppState},
-    File, Task,
+    sink::QueueItem,
+    zones, Action, Exception, Fade, File, FileInfo, Missed, Playlist, Task, Urgency,
 };
 use askama::Template;
 use axum::{
@@ -15,13 +18,17 @@ use axum::{
     },
     Form,
 };
-use chrono::{DateTime, Local, NaiveDateTime};
+use chrono::{DateTime, Datelike, Local, NaiveDate, NaiveDateTime, NaiveTime, Weekday};
 use futures_util::{Stream, StreamExt};
+use rusqlite::Connection;
 use std::{collections::HashMap, convert::Infallible};
 
 /// the format for sending dates to the frontend
 pub static DATEFMT: &str = "%Y-%m-%dT%H:%M";
 
+/// short hungarian names of the days of the week, starting on monday
+static WEEKDAY_NAMES: [&str; 7] = ["H", "K", "Sze", "Cs", "P", "Szo", "V"];
+
 /// parse a datetime string in DATEFMT format to a local DateTime
 fn parse_local_datetime(s: &str) -> Result<DateTime<Local>, chrono::ParseError> {
     NaiveDateTime::parse_from_str(s, DATEFMT).map(|naive| naive.and_local_timezone(Local).unwrap())
@@ -30,107 +37,459 @@ fn parse_local_datetime(s: &str) -> Result<DateTime<Local>, chrono::ParseError>
 #[derive(Template)]
 #[template(path = "index.html")]
 pub struct Index {
-    np: Option<NowPlaying>,
+    pub status: Status,
+    pub queue: Queue,
     pub tasks: Tasks,
-    pub files: Vec<String>,
-    pub time: Time,
+    pub files: Vec<FileInfo>,
+    pub loudness: LoudnessTarget,
+    pub calendar: Calendar,
+    pub profiles: Profiles,
+    pub playlists: Playlists,
+    pub pause: Pause,
+    pub volume: Volume,
+    pub form: TaskForm,
+    pub timeline: Timeline,
 }
 impl Index {
     pub async fn get(State(p): AppState) -> Result<impl IntoResponse, Response> {
         let mut lock = p.lock().await;
-        let tasks = lock.list_tasks()?;
-        let files = lock.list_files()?;
+        let tasks = Tasks::load(&mut lock)?;
+        let files = Files::load(&mut lock)?.files;
+        let loudness = LoudnessTarget::load(&mut lock)?;
+        let entries = lock.list_calendar()?;
+        let profiles = Profiles::load(&mut lock)?;
+        let playlists = Playlists::load(&mut lock)?;
+        let pause = Pause::load(&mut lock)?;
+        let volume = Volume::load(&p);
+        let form = TaskForm::load(&mut lock, None)?;
+        let now = lock.clock.now();
+        let timeline = Timeline::load(
+            &lock.lock,
+            now,
+            now + chrono::Duration::days(Timeline::DEFAULT_DAYS),
+        )?;
         drop(lock);
 
-        let np = p.now_playing().as_ref().map(Clone::clone);
-
         Ok(Self {
-            np,
-            tasks: Tasks::new(tasks),
+            status: Status::load(&p),
+            queue: Queue::load(&p),
+            tasks,
             files,
-            time: Time::default(),
+            loudness,
+            calendar: Calendar { entries },
+            profiles,
+            playlists,
+            pause,
+            volume,
+            form,
+            timeline,
+        })
+    }
+}
+
+#[derive(Template)]
+#[template(path = "timeline.html")]
+pub struct Timeline {
+    /// the plays, grouped by day
+    pub days: Vec<(NaiveDate, Vec<scheduler::Event>)>,
+    pub conflicts: Vec<scheduler::Conflict>,
+    /// the number of days shown
+    pub len: i64,
+}
+impl Timeline {
+    pub const DEFAULT_DAYS: i64 = 7;
+
+    fn load(conn: &Connection, from: DateTime<Local>, to: DateTime<Local>) -> Result<Self, Response> {
+        let events = scheduler::timeline(conn, from, to).map_err(|e| {
+            err_to_reply(
+                e,
+                "Timeline",
+                "Failed to get timeline",
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        })?;
+
+        let conflicts = scheduler::conflicts(conn, from, to).map_err(|e| {
+            err_to_reply(
+                e,
+                "Conflicts",
+                "Failed to get conflicts",
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        })?;
+
+        let mut days: Vec<(NaiveDate, Vec<scheduler::Event>)> = Vec::new();
+        for e in events {
+            let date = e.time.date_naive();
+            match days.last_mut() {
+                Some((d, events)) if *d == date => events.push(e),
+                _ => days.push((date, vec![e])),
+            }
+        }
+        Ok(Self {
+            days,
+            conflicts,
+            len: (to - from).num_days(),
         })
     }
+    pub async fn get(
+        State(p): AppState,
+        Query(q): Query<HashMap<String, String>>,
+    ) -> Result<impl IntoResponse, Response> {
+        let (from, to) = query_range(&q, Self::DEFAULT_DAYS, p.clock.now())
+            .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()).into_response())?;
+        Self::load(&p.lock().await.lock, from, to)
+    }
+    pub fn day_label(&self, date: &NaiveDate) -> String {
+        let day = WEEKDAY_NAMES[date.weekday().num_days_from_monday() as usize];
+        format!("{date} ({day})")
+    }
+    pub fn suppressed_label(&self, e: &scheduler::Event) -> &'static str {
+        match e.suppressed {
+            Some("calendar") => "elmarad: naptár",
+            Some("profile") => "elmarad: csengetési rend",
+            Some("paused") => "elmarad: szüneteltetve",
+            _ => "",
+        }
+    }
 }
 
 #[derive(Template)]
 #[template(path = "status.html")]
 pub struct Status {
-    np: Option<NowPlaying>,
+    /// the outputs playing something
+    np: Vec<(String, NowPlaying)>,
+    /// show the names of the outputs, if there's more than one
+    named: bool,
+    /// the outputs whose device isn't working
+    down: Vec<String>,
 }
 impl Status {
+    pub fn load(p: &Player) -> Self {
+        Self {
+            np: p.now_playing(),
+            named: p.has_outputs(),
+            down: p.outputs_down(),
+        }
+    }
     pub async fn get(State(p): AppState) -> impl IntoResponse {
-        let np = p.now_playing().to_owned();
-        Self { np }
+        Self::load(&p)
     }
 
     pub async fn sse(State(p): AppState) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+        let named = p.has_outputs();
         let stream = p.np_stream();
-        let stream = stream.map(|np| {
-            let s = Self { np }.render().unwrap();
+        let stream = stream.map(move |(np, down)| {
+            let s = Self { np, named, down }.render().unwrap();
             Ok(Event::default().data(s.replace('\n', " ")))
         });
         Sse::new(stream).keep_alive(KeepAlive::default())
     }
 
     pub async fn realtime(State(p): AppState) -> impl IntoResponse {
-        let mut rx = p.np_realtime();
-        rx.changed().await.unwrap();
-        let np = rx.borrow().to_owned();
-        let mut res = (Self { np }).into_response();
+        p.np_changed().await;
+        let mut res = Self::load(&p).into_response();
         res.headers_mut()
             .insert("HX-Trigger", HeaderValue::from_static("realtime"));
 
         res
     }
+
+    /// hold the track playing on `?output=` where it is
+    pub async fn pause(
+        State(p): AppState,
+        Query(q): Query<HashMap<String, String>>,
+    ) -> Result<impl IntoResponse, Response> {
+        let output = Queue::output(&q);
+        let paused = p
+            .pause(output)
+            .map_err(|e| (StatusCode::NOT_FOUND, e.to_string()).into_response())?;
+        if !paused {
+            return Err((StatusCode::CONFLICT, "Nothing to pause").into_response());
+        }
+        info!("pause {output:?}");
+        Ok(Self::load(&p))
+    }
+    pub async fn resume(
+        State(p): AppState,
+        Query(q): Query<HashMap<String, String>>,
+    ) -> Result<impl IntoResponse, Response> {
+        let output = Queue::output(&q);
+        p.resume(output)
+            .map_err(|e| (StatusCode::NOT_FOUND, e.to_string()).into_response())?;
+        info!("resume {output:?}");
+        Ok(Self::load(&p))
+    }
+}
+
+#[derive(Template)]
+#[template(path = "queue.html")]
+pub struct Queue {
+    /// the tracks waiting on each output
+    outputs: Vec<(String, Vec<QueueItem>)>,
+    /// show the names of the outputs, if there's more than one
+    named: bool,
+}
+impl Queue {
+    pub fn load(p: &Player) -> Self {
+        Self {
+            outputs: p.queue(),
+            named: p.has_outputs(),
+        }
+    }
+    fn output(q: &HashMap<String, String>) -> Option<&str> {
+        q.get("output")
+            .map(String::as_str)
+            .filter(|s| !s.is_empty())
+    }
+
+    pub async fn get(State(p): AppState) -> impl IntoResponse {
+        Self::load(&p)
+    }
+    /// empty the queue, the current track plays on
+    pub async fn delete(
+        State(p): AppState,
+        Query(q): Query<HashMap<String, String>>,
+    ) -> Result<impl IntoResponse, Response> {
+        p.clear_queue(Self::output(&q))
+            .map_err(|e| (StatusCode::NOT_FOUND, e.to_string()).into_response())?;
+        info!("queue cleared {:?}", Self::output(&q));
+        Ok(Self::load(&p))
+    }
+    pub async fn delete_item(
+        State(p): AppState,
+        Path(id): Path<u64>,
+    ) -> Result<impl IntoResponse, Response> {
+        if !p.remove_queued(id) {
+            return Err((StatusCode::NOT_FOUND, "Track not found").into_response());
+        }
+        Ok(Self::load(&p))
+    }
+    pub async fn put_position(
+        State(p): AppState,
+        Path(id): Path<u64>,
+        Form(f): Form<HashMap<String, String>>,
+    ) -> Result<impl IntoResponse, Response> {
+        let Some(Ok(position)) = f.get("position").map(|s| s.parse::<usize>()) else {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "Missing or invalid value `position`",
+            )
+                .into_response());
+        };
+        if !p.reorder_queued(id, position) {
+            return Err((StatusCode::NOT_FOUND, "Track not found").into_response());
+        }
+        Ok(Self::load(&p))
+    }
+    /// fade out the current track, the queue goes on
+    pub async fn skip(
+        State(p): AppState,
+        Query(q): Query<HashMap<String, String>>,
+    ) -> Result<impl IntoResponse, Response> {
+        p.skip(Self::output(&q))
+            .map_err(|e| (StatusCode::NOT_FOUND, e.to_string()).into_response())?;
+        info!("skip {:?}", Self::output(&q));
+        Ok(Self::load(&p))
+    }
+}
+
+#[derive(Template)]
+#[template(path = "pause.html")]
+pub struct Pause {
+    /// the end of the pause of all schedules, `None` if they're running
+    pub until: Option<DateTime<Local>>,
+}
+impl Pause {
+    fn load(lock: &mut PlayerLock<'_>) -> Result<Self, Response> {
+        let until = db::get_paused_until(&lock.lock).map_err(|e| {
+            err_to_reply(
+                e.into(),
+                "Get pause",
+                "Failed to get pause",
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        })?;
+        let now = lock.clock.now();
+        Ok(Self {
+            until: until.filter(|until| *until > now),
+        })
+    }
+
+    pub async fn get(State(p): AppState) -> Result<impl IntoResponse, Response> {
+        Self::load(&mut p.lock().await)
+    }
+    pub async fn post(
+        State(p): AppState,
+        Form(f): Form<HashMap<String, String>>,
+    ) -> Result<impl IntoResponse, Response> {
+        let Some(Ok(until)) = f.get("until").map(|s| parse_local_datetime(s)) else {
+            return Err(
+                (StatusCode::BAD_REQUEST, "Missing or invalid value `until`").into_response(),
+            );
+        };
+        Self::set(p, Some(until)).await
+    }
+    pub async fn delete(State(p): AppState) -> Result<impl IntoResponse, Response> {
+        Self::set(p, None).await
+    }
+    async fn set(p: Player, until: Option<DateTime<Local>>) -> Result<Self, Response> {
+        let mut lock = p.lock().await;
+        if let Err(e) = scheduler::pause(&lock.lock, &p, until) {
+            error!("pause: {e:#?}");
+            return Err((StatusCode::BAD_REQUEST, e.to_string()).into_response());
+        }
+        Self::load(&mut lock)
+    }
+}
+
+#[derive(Template)]
+#[template(path = "volume.html")]
+pub struct Volume {
+    /// the master volume in percent
+    pub percent: u32,
+    /// seconds
+    pub stop_fade: f32,
+}
+impl Volume {
+    fn load(p: &Player) -> Self {
+        Self {
+            percent: (p.volume() * 100.0).round() as u32,
+            stop_fade: p.stop_fade(),
+        }
+    }
+
+    pub async fn get(State(p): AppState) -> impl IntoResponse {
+        Self::load(&p)
+    }
+    pub async fn put(
+        State(p): AppState,
+        Form(f): Form<HashMap<String, String>>,
+    ) -> Result<impl IntoResponse, Response> {
+        let Some(Ok(percent)) = f.get("volume").map(|s| s.parse::<u32>()) else {
+            return Err(
+                (StatusCode::BAD_REQUEST, "Missing or invalid value `volume`").into_response(),
+            );
+        };
+        let stop_fade = parse_fade(&f, "stop_fade")
+            .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()).into_response())?;
+        let conn = p.conn.lock().await;
+        if let Err(e) = p.set_volume(&conn, percent as f32 / 100.0) {
+            error!("volume: {e:#?}");
+            return Err((StatusCode::BAD_REQUEST, e.to_string()).into_response());
+        }
+        if let Err(e) = p.set_stop_fade(&conn, stop_fade) {
+            error!("stop fade: {e:#?}");
+            return Err((StatusCode::BAD_REQUEST, e.to_string()).into_response());
+        }
+        drop(conn);
+        info!("volume: {percent}%, stop fade: {stop_fade}s");
+        Ok(Self::load(&p))
+    }
 }
 
 #[derive(Template)]
 #[template(path = "datepicker.html")]
 pub struct DatePicker {
     pub time: Time,
+    pub profiles: Profiles,
+    /// selected profile, empty for none
+    pub profile: String,
+    /// the first and last day of a recurring task, empty for none
+    pub valid_from: String,
+    pub valid_until: String,
+    /// the tasks a relative one can follow
+    pub anchors: Vec<String>,
 }
 #[derive(Default)]
 pub enum Time {
     #[default]
     Now,
     Scheduled(DateTime<Local>),
-    Recurring(Vec<DateTime<Local>>),
+    Recurring(Vec<DateTime<Local>>, Vec<Weekday>),
+    Cron(String),
+    /// the task to follow, empty for now, and the offset in minutes
+    Relative(String, i64),
 }
 impl Time {
     pub fn is_now(&self) -> bool {
         match self {
             Time::Now => true,
             Time::Scheduled(_) => false,
-            Time::Recurring(_) => false,
+            Time::Recurring(..) => false,
+            Time::Cron(_) => false,
+            Time::Relative(..) => false,
         }
     }
     pub fn is_scheduled(&self) -> bool {
         match self {
             Time::Now => false,
             Time::Scheduled(_) => true,
-            Time::Recurring(_) => false,
+            Time::Recurring(..) => false,
+            Time::Cron(_) => false,
+            Time::Relative(..) => false,
         }
     }
     pub fn is_recurring(&self) -> bool {
         match self {
             Time::Now => false,
             Time::Scheduled(_) => false,
-            Time::Recurring(_) => true,
+            Time::Recurring(..) => true,
+            Time::Cron(_) => false,
+            Time::Relative(..) => false,
         }
     }
+    pub fn is_cron(&self) -> bool {
+        match self {
+            Time::Now => false,
+            Time::Scheduled(_) => false,
+            Time::Recurring(..) => false,
+            Time::Cron(_) => true,
+            Time::Relative(..) => false,
+        }
+    }
+    pub fn is_relative(&self) -> bool {
+        match self {
+            Time::Now => false,
+            Time::Scheduled(_) => false,
+            Time::Recurring(..) => false,
+            Time::Cron(_) => false,
+            Time::Relative(..) => true,
+        }
+    }
+    /// whether a relative time is before its anchor
+    pub fn is_before(&self) -> bool {
+        matches!(self, Time::Relative(_, offset) if *offset < 0)
+    }
+    pub fn is_anchor(&self, name: &str) -> bool {
+        matches!(self, Time::Relative(anchor, _) if anchor == name)
+    }
+    /// every weekday with its label and whether it's selected
+    pub fn weekdays(&self) -> Vec<(Weekday, &'static str, bool)> {
+        let selected = match self {
+            Time::Recurring(_, days) => days.as_slice(),
+            _ => &[],
+        };
+        all_days()
+            .into_iter()
+            .zip(WEEKDAY_NAMES)
+            .map(|(d, label)| (d, label, selected.contains(&d)))
+            .collect()
+    }
 }
 impl DatePicker {
     pub async fn get(
+        State(p): AppState,
         Query(q): Query<HashMap<String, String>>,
     ) -> Result<impl IntoResponse, Response> {
+        let now = p.clock.now();
         let time = match q.get("type").map(String::as_str).unwrap_or("now") {
             "now" => Time::Now,
             "scheduled" => {
                 // carry over first recurring date
                 let t = match q.get("time-0") {
-                    Some(s) => parse_local_datetime(s).unwrap_or_else(|_| Local::now()),
-                    None => Local::now(),
+                    Some(s) => parse_local_datetime(s).unwrap_or(now),
+                    None => now,
                 };
                 Time::Scheduled(t)
             }
@@ -148,17 +507,42 @@ impl DatePicker {
 
                 query_times(&q, &mut times);
 
+                // carry over the selected days, or default to school days
+                let days = if q.contains_key("recurring-n") {
+                    query_days(&q)
+                } else {
+                    all_days().into_iter().take(5).collect()
+                };
+
                 if times.iter().any(Option::is_none) {
-                    Time::Recurring(vec![Local::now(); n])
+                    Time::Recurring(vec![now; n], days)
                 } else {
                     let mut times = times.into_iter().map(Option::unwrap).collect::<Vec<_>>();
-                    times.resize(n, Local::now());
-                    Time::Recurring(times)
+                    times.resize(n, now);
+                    Time::Recurring(times, days)
                 }
             }
+            "cron" => Time::Cron(q.get("cron").cloned().unwrap_or_default()),
+            "relative" => Time::Relative(
+                q.get("anchor").cloned().unwrap_or_default(),
+                parse_offset(&q).unwrap_or(10),
+            ),
             _ => return Err((StatusCode::BAD_REQUEST, "Invalid value for `type`").into_response()),
         };
-        Ok(Self { time })
+        let mut lock = p.lock().await;
+        let anchors = anchor_names(&mut lock)?;
+        let profiles = Profiles::load(&mut lock)?;
+        let profile = q.get("profile").cloned().unwrap_or_default();
+        let valid_from = q.get("valid_from").cloned().unwrap_or_default();
+        let valid_until = q.get("valid_until").cloned().unwrap_or_default();
+        Ok(Self {
+            time,
+            profiles,
+            profile,
+            valid_from,
+            valid_until,
+            anchors,
+        })
     }
 }
 
@@ -166,21 +550,108 @@ impl DatePicker {
 #[template(path = "filepicker.html")]
 pub struct FilePicker {
     pub files: Vec<String>,
+    pub playlists: Vec<String>,
+    /// selected file or playlist
+    pub file: String,
 }
 
 #[derive(Template)]
 #[template(path = "form.html")]
 pub struct TaskForm {
     pub files: Vec<String>,
+    pub playlists: Vec<String>,
     pub time: Time,
+    pub profiles: Profiles,
+    pub profile: String,
+    pub valid_from: String,
+    pub valid_until: String,
+    pub anchors: Vec<String>,
+    pub name: String,
+    pub file: String,
+    /// the checked zones, and all of them
+    pub zones: Vec<String>,
+    pub all_zones: Vec<String>,
+    pub missed: Missed,
+    /// dB
+    pub gain: f32,
+    pub fade: Fade,
+    pub urgency: Urgency,
+    /// the name of the task being edited
+    pub edit: Option<String>,
+    /// only render the fields, to be swapped into an existing form
+    pub oob: bool,
 }
 impl TaskForm {
     pub async fn get(State(p): AppState) -> Result<impl IntoResponse, Response> {
-        let files = p.lock().await.list_files()?;
-        Ok(Self {
+        Self::load(&mut p.lock().await, None)
+    }
+    fn load(lock: &mut PlayerLock<'_>, task: Option<Task>) -> Result<Self, Response> {
+        let files = lock.list_files()?;
+        let playlists = playlist_names(lock)?;
+        let anchors = anchor_names(lock)?;
+        let profiles = Profiles::load(lock)?;
+        let mut form = Self {
             files,
+            playlists,
             time: Time::default(),
-        })
+            profiles,
+            profile: String::new(),
+            valid_from: String::new(),
+            valid_until: String::new(),
+            anchors,
+            name: String::new(),
+            file: String::new(),
+            zones: zones::names().into_iter().take(1).collect(),
+            all_zones: zones::names(),
+            missed: Missed::default(),
+            gain: 0.0,
+            fade: Fade::default(),
+            urgency: Urgency::default(),
+            edit: None,
+            oob: false,
+        };
+        let Some(task) = task else {
+            return Ok(form);
+        };
+
+        form.name = task.get_name().to_owned();
+        form.file = task.get_file_name().to_owned();
+        form.zones = task.get_zones().to_vec();
+        form.missed = task.get_missed().unwrap_or_default();
+        form.gain = task.get_gain();
+        form.fade = task.get_fade();
+        form.urgency = task.get_urgency();
+        form.profile = task.get_profile().unwrap_or_default().to_owned();
+        form.valid_from = task
+            .get_valid_from()
+            .map(|d| d.to_string())
+            .unwrap_or_default();
+        form.valid_until = task
+            .get_valid_until()
+            .map(|d| d.to_string())
+            .unwrap_or_default();
+        form.edit = Some(form.name.clone());
+        form.time = match task {
+            Task::Now { .. } => Time::Now,
+            Task::Scheduled {
+                anchor: Some(anchor),
+                ..
+            } => Time::Relative(anchor.task, anchor.offset / 60),
+            Task::Scheduled { time, .. } => Time::Scheduled(time),
+            Task::Recurring { time, days, .. } => {
+                let today = lock.clock.now().date_naive();
+                let times = time
+                    .into_iter()
+                    .filter_map(|t| today.and_time(t).and_local_timezone(Local).earliest())
+                    .collect();
+                Time::Recurring(times, days)
+            }
+            Task::Cron { expr, .. } => Time::Cron(expr),
+        };
+        Ok(form)
+    }
+    fn oob(self) -> Self {
+        Self { oob: true, ..self }
     }
 }
 
@@ -188,63 +659,146 @@ impl TaskForm {
 #[template(path = "tasks.html")]
 pub struct Tasks {
     pub tasks: Vec<Task>,
+    /// expired recurring tasks, not scheduled anymore
+    pub archived: Vec<Task>,
+    pub today: NaiveDate,
     pub elapsed: Vec<String>,
     pub refr: u32,
+    pub exceptions: Vec<Exception>,
+    pub files: Vec<String>,
+    pub playlists: Vec<String>,
 }
 impl Tasks {
-    fn new(tasks: Vec<Task>) -> Self {
+    fn load(lock: &mut PlayerLock<'_>) -> Result<Self, Response> {
+        let tasks = lock.list_tasks()?;
+        let archived = db::list_archived_tasks(&lock.lock).map_err(|e| {
+            err_to_reply(
+                e.into(),
+                "List archived tasks",
+                "Failed to get archived tasks",
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        })?;
+        let files = lock.list_files()?;
+        let playlists = playlist_names(lock)?;
+        let exceptions = db::list_exceptions(&lock.lock, None).map_err(|e| {
+            err_to_reply(
+                e.into(),
+                "List exceptions",
+                "Failed to get exceptions",
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        })?;
+
         let (elapsed, refr): (Vec<_>, Vec<_>) = tasks
             .iter()
-            .map(filters::task_elapsed)
+            .map(|t| filters::task_elapsed(t, &exceptions, lock.clock.now()))
             .map(Result::unwrap)
             .unzip();
 
         let refr = refr.into_iter().filter(|n| *n > 0).min().unwrap_or(0);
 
-        Self {
+        Ok(Self {
             tasks,
+            archived,
+            today: lock.clock.now().date_naive(),
             elapsed,
             refr,
+            exceptions,
+            files,
+            playlists,
+        })
+    }
+    /// whether the last day of the task has passed
+    pub fn is_expired(&self, task: &Task) -> bool {
+        task.get_valid_until()
+            .is_some_and(|until| until < self.today)
+    }
+    pub fn exceptions_of(&self, task: &Task) -> Vec<&Exception> {
+        self.exceptions
+            .iter()
+            .filter(|e| &e.task == task.get_name())
+            .collect()
+    }
+    /// the times of a recurring task, formatted for the exception form
+    pub fn times_of(&self, task: &Task) -> Vec<String> {
+        match task {
+            Task::Recurring { time, .. } => time
+                .iter()
+                .map(|t| t.format(db::TIMEFMT).to_string())
+                .collect(),
+            _ => Vec::new(),
         }
     }
     pub async fn get(State(p): AppState) -> Result<impl IntoResponse, Response> {
-        let tasks = p.lock().await.list_tasks()?;
-        Ok(Self::new(tasks))
+        Self::load(&mut p.lock().await)
     }
     pub async fn post(
         State(p): AppState,
         Form(f): Form<HashMap<String, String>>,
     ) -> Result<impl IntoResponse, Response> {
-        let res = Self::post_inner(p.clone(), f).await;
-        if let Err(e) = res {
-            error!("post task: {e:#?}");
-            return Err((StatusCode::BAD_REQUEST, e.to_string()).into_response());
+        let conflicts = match Self::post_inner(p.clone(), f).await {
+            Ok(conflicts) => conflicts,
+            Err(e) => {
+                error!("post task: {e:#?}");
+                return Err((StatusCode::BAD_REQUEST, e.to_string()).into_response());
+            }
+        };
+
+        let res = Tasks::get(State(p)).await?.into_response();
+        Ok(with_conflict_warning(res, conflicts))
+    }
+    /// returns whether the new task conflicts with others
+    async fn post_inner(p: Player, f: HashMap<String, String>) -> anyhow::Result<bool> {
+        let conn = p.conn.lock().await;
+        let task = Self::parse_form(&conn, f, p.clock.now())?;
+        let mut conflicts = false;
+        if !task.is_now() {
+            conflicts = check_conflicts(&conn, &task, None, p.clock.now())?;
+            db::insert_task(&conn, &task, p.clock.now()).map_err(db::db_err)?;
         }
+        drop(conn);
 
-        Tasks::get(State(p)).await
+        // record task creation metric
+        m::record_task_created(task.get_type());
+
+        schedule(task, p).await?;
+        Ok(conflicts)
     }
-    async fn post_inner(p: Player, mut f: HashMap<String, String>) -> anyhow::Result<()> {
+    /// build a task from the fields of the task form
+    fn parse_form(
+        conn: &Connection,
+        mut f: HashMap<String, String>,
+        now: DateTime<Local>,
+    ) -> anyhow::Result<Task> {
         let Some(name) = f.remove("name") else {
             anyhow::bail!("Missing value `name`")
         };
-        let Some(Some(priority)) = f.remove("priority").map(|s| {
-            Some(match s.as_str() {
-                "false" | "0" | "off" => false,
-                "true" | "1" | "on" => true,
-                _ => return None,
-            })
-        }) else {
-            anyhow::bail!("Missing or invalid value `priority`")
-        };
+        let zones = query_zones(&f);
+        if zones.is_empty() {
+            anyhow::bail!("Select at least one zone")
+        }
         let Some(file_name) = f.remove("file_name") else {
             anyhow::bail!("Missing value `file_name`")
         };
+        let gain = parse_gain(&f)?;
+        let fade = Fade {
+            fade_in: parse_fade(&f, "fade_in")?,
+            fade_out: parse_fade(&f, "fade_out")?,
+        };
+        let urgency = match f.get("urgency") {
+            Some(s) => s.parse()?,
+            None => Urgency::default(),
+        };
 
         let task: Task = match f.get("type").map(String::as_str).unwrap_or("now") {
             "now" => Task::Now {
                 name,
-                priority,
+                zones,
                 file_name,
+                gain,
+                fade,
+                urgency,
             },
             "scheduled" => {
                 if name.trim().is_empty() {
@@ -255,19 +809,47 @@ impl Tasks {
                 };
 
                 // check if scheduled task is in the future
-                (time - Local::now())
+                (time - now)
                     .to_std()
                     .map_err(|_| anyhow::anyhow!("Date is in the past"))?;
 
-                let task = Task::Scheduled {
+                Task::Scheduled {
                     name,
-                    priority,
+                    zones,
                     file_name,
                     time,
+                    missed: parse_missed(&f)?,
+                    anchor: None,
+                    gain,
+                    fade,
+                    urgency,
+                }
+            }
+            "relative" => {
+                if name.trim().is_empty() {
+                    anyhow::bail!("`name` can't be empty")
                 };
+                let Some(offset) = parse_offset(&f) else {
+                    anyhow::bail!("Missing or invalid value `offset`")
+                };
+                let anchor = f.remove("anchor").filter(|s| !s.is_empty());
+                if anchor.as_ref() == Some(&name) {
+                    anyhow::bail!("A task can't follow itself")
+                }
+                let (time, anchor) =
+                    scheduler::resolve(conn, anchor, chrono::Duration::minutes(offset), now)?;
 
-                db::insert_task(&*p.conn.lock().await, &task).map_err(db::db_err)?;
-                task
+                Task::Scheduled {
+                    name,
+                    zones,
+                    file_name,
+                    time,
+                    missed: parse_missed(&f)?,
+                    anchor,
+                    gain,
+                    fade,
+                    urgency,
+                }
             }
             "recurring" => {
                 if name.trim().is_empty() {
@@ -294,86 +876,356 @@ impl Tasks {
 
                 let time = times.iter().map(DateTime::time).collect();
 
-                let task = Task::Recurring {
+                let days = query_days(&f);
+                if days.is_empty() {
+                    anyhow::bail!("Select at least one day")
+                }
+
+                let profile = f.remove("profile").filter(|s| !s.is_empty());
+                if let Some(profile) = &profile {
+                    if !db::list_profiles(conn)?.contains(profile) {
+                        anyhow::bail!("Profile not found")
+                    }
+                }
+
+                let valid_from = parse_date(&f, "valid_from")?;
+                let valid_until = parse_date(&f, "valid_until")?;
+                if let (Some(from), Some(until)) = (valid_from, valid_until) {
+                    if until < from {
+                        anyhow::bail!("`valid_until` can't be before `valid_from`")
+                    }
+                }
+
+                Task::Recurring {
                     name,
-                    priority,
+                    zones,
                     file_name,
                     time,
+                    days,
+                    profile,
+                    missed: parse_missed(&f)?,
+                    valid_from,
+                    valid_until,
+                    gain,
+                    fade,
+                    urgency,
+                }
+            }
+            "cron" => {
+                if name.trim().is_empty() {
+                    anyhow::bail!("`name` can't be empty")
                 };
+                let Some(expr) = f.remove("cron").map(|s| s.trim().to_owned()) else {
+                    anyhow::bail!("Missing value `cron`")
+                };
+                parse_cron(&expr)?;
 
-                db::insert_task(&*p.conn.lock().await, &task).map_err(db::db_err)?;
-                task
+                Task::Cron {
+                    name,
+                    zones,
+                    file_name,
+                    expr,
+                    missed: parse_missed(&f)?,
+                    gain,
+                    fade,
+                    urgency,
+                }
             }
             _ => anyhow::bail!("Invalid value for `type`"),
         };
-
-        // record task creation metric
-        m::record_task_created(task.get_type());
-
-        schedule(task, p).await?;
-        Ok(())
+        Ok(task)
     }
 
-    pub async fn delete(
+    /// edit an existing task, replacing its running schedule
+    pub async fn put(
         State(p): AppState,
         Path(name): Path<String>,
+        Form(f): Form<HashMap<String, String>>,
     ) -> Result<impl IntoResponse, Response> {
-        match p.db_name(db::delete_task, &name).await {
-            Ok(v) => {
-                if v {
-                    if let Err(e) = p.cancel(&name).await {
-                        return Err(err_to_reply(
-                            e,
-                            &name,
-                            "Failed to issue cancel",
-                            StatusCode::INTERNAL_SERVER_ERROR,
-                        ));
-                    }
-                } else {
-                    return Err((StatusCode::NOT_FOUND, "Task not found").into_response());
-                }
-            }
+        let conflicts = match Self::put_inner(p.clone(), &name, f).await {
+            Ok(conflicts) => conflicts,
             Err(e) => {
-                return Err(err_to_reply(
-                    e.into(),
-                    &name,
-                    "Failed to delete task",
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                ))
+                error!("{name}: edit task: {e:#?}");
+                return Err((StatusCode::BAD_REQUEST, e.to_string()).into_response());
             }
         };
 
-        Tasks::get(State(p)).await
+        // send back the list, and reset the form
+        let mut lock = p.lock().await;
+        let tasks = Tasks::load(&mut lock)?;
+        let form = TaskForm::load(&mut lock, None)?;
+        drop(lock);
+        let res = render_all(&[&tasks, &form.oob()])?.into_response();
+        Ok(with_conflict_warning(res, conflicts))
     }
-}
+    /// returns whether the edited task conflicts with others
+    async fn put_inner(p: Player, name: &str, f: HashMap<String, String>) -> anyhow::Result<bool> {
+        // holding the db lock for the whole swap makes it atomic for the scheduler:
+        // a task only plays after checking for cancellation under the same lock
+        let conn = p.conn.lock().await;
+        let task = Self::parse_form(&conn, f, p.clock.now())?;
+        if task.is_now() {
+            anyhow::bail!("Can't change a task to `now`")
+        }
+        let conflicts = check_conflicts(&conn, &task, Some(name), p.clock.now())?;
 
-#[derive(Template)]
-#[template(path = "files.html")]
-pub struct Files {
-    pub files: Vec<String>,
-}
-impl Files {
-    pub async fn get(State(p): AppState) -> Result<impl IntoResponse, Response> {
-        let files = p.lock().await.list_files()?;
-        Ok(Self { files })
+        if !db::update_task(&conn, name, &task, p.clock.now()).map_err(db::db_err)? {
+            anyhow::bail!("Task not found")
+        }
+        p.cancel(name).await?;
+        info!("{name}: edited, rescheduling as {}", task.get_name());
+
+        let following = scheduler::reanchor(&conn, p.clock.now(), name, task.get_name());
+        schedule(task, p.clone()).await?;
+        for task in following {
+            reschedule(task, &p).await?;
+        }
+        Ok(conflicts)
     }
-    pub async fn post(
+    /// add an exception to a single occurrence of a recurring task
+    pub async fn post_exception(
         State(p): AppState,
-        mut form: Multipart,
+        Path(name): Path<String>,
+        Form(f): Form<HashMap<String, String>>,
     ) -> Result<impl IntoResponse, Response> {
-        while let Some(field) = form.next_field().await.unwrap() {
-            let name = field.name().unwrap().to_string();
-            if name != "file" {
-                continue;
-            };
+        let res = Self::post_exception_inner(p.clone(), &name, f).await;
+        if let Err(e) = res {
+            error!("{name}: post exception: {e:#?}");
+            return Err((StatusCode::BAD_REQUEST, e.to_string()).into_response());
+        }
+
+        Tasks::get(State(p)).await
+    }
+    async fn post_exception_inner(
+        p: Player,
+        name: &str,
+        mut f: HashMap<String, String>,
+    ) -> anyhow::Result<()> {
+        let parse_time = |s: &str| NaiveTime::parse_from_str(s, db::TIMEFMT);
+
+        let Some(Ok(date)) = f.get("date").map(|s| s.parse::<NaiveDate>()) else {
+            anyhow::bail!("Missing or invalid value `date`")
+        };
+        let Some(Ok(time)) = f.get("time").map(|s| parse_time(s)) else {
+            anyhow::bail!("Missing or invalid value `time`")
+        };
+        let action = match f.get("action").map(String::as_str) {
+            Some("skip") => Action::Skip,
+            Some("move") => {
+                let Some(Ok(to)) = f.get("to").map(|s| parse_time(s)) else {
+                    anyhow::bail!("Missing or invalid value `to`")
+                };
+                Action::Move { to }
+            }
+            Some("replace") => {
+                let Some(file_name) = f.remove("file_name") else {
+                    anyhow::bail!("Missing value `file_name`")
+                };
+                Action::Replace { file_name }
+            }
+            _ => anyhow::bail!("Missing or invalid value `action`"),
+        };
+
+        let conn = p.conn.lock().await;
+        let task = db::get_task(&conn, name)?;
+        let Task::Recurring {
+            time: times, days, ..
+        } = &task
+        else {
+            anyhow::bail!("Only recurring tasks can have exceptions")
+        };
+        if date < p.clock.now().date_naive() {
+            anyhow::bail!("Date is in the past")
+        }
+        if !times.contains(&time) || !days.contains(&date.weekday()) {
+            anyhow::bail!("The task doesn't play at that time")
+        }
+        if let Action::Replace { file_name } = &action {
+            if !db::list_files(&conn)?.contains(file_name)
+                && db::get_playlist(&conn, file_name)?.is_none()
+            {
+                anyhow::bail!("File not found")
+            }
+        }
+
+        let exception = Exception {
+            id: 0,
+            task: name.to_owned(),
+            date,
+            time,
+            action,
+        };
+        db::insert_exception(&conn, &exception).map_err(|e| match e {
+            rusqlite::Error::SqliteFailure(
+                rusqlite::ffi::Error {
+                    code: rusqlite::ErrorCode::ConstraintViolation,
+                    ..
+                },
+                _,
+            ) => anyhow::anyhow!("That occurrence already has an exception"),
+            e => db::db_err(e),
+        })?;
+        info!("{name}: exception added for {date} {time}");
+
+        let following = scheduler::reanchor(&conn, p.clock.now(), name, name);
+        reschedule(task, &p).await?;
+        for task in following {
+            reschedule(task, &p).await?;
+        }
+        Ok(())
+    }
+    pub async fn delete_exception(
+        State(p): AppState,
+        Path(id): Path<i64>,
+    ) -> Result<impl IntoResponse, Response> {
+        let conn = p.conn.lock().await;
+        let name = match db::delete_exception(&conn, id) {
+            Ok(Some(name)) => name,
+            Ok(None) => return Err((StatusCode::NOT_FOUND, "Exception not found").into_response()),
+            Err(e) => {
+                return Err(err_to_reply(
+                    e.into(),
+                    &id.to_string(),
+                    "Failed to delete exception",
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                ))
+            }
+        };
+        let res = match db::get_task(&conn, &name) {
+            Ok(task) => {
+                let following = scheduler::reanchor(&conn, p.clock.now(), &name, &name);
+                let mut res = reschedule(task, &p).await;
+                for task in following {
+                    res = res.and(reschedule(task, &p).await);
+                }
+                res
+            }
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = res {
+            return Err(err_to_reply(
+                e,
+                &name,
+                "Failed to reschedule task",
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ));
+        }
+        drop(conn);
+
+        Tasks::get(State(p)).await
+    }
+
+    /// the task form, filled in with an existing task
+    pub async fn edit(
+        State(p): AppState,
+        Path(name): Path<String>,
+    ) -> Result<impl IntoResponse, Response> {
+        let mut lock = p.lock().await;
+        let task = match db::get_task(&lock.lock, &name) {
+            Ok(task) => task,
+            Err(rusqlite::Error::QueryReturnedNoRows) => {
+                return Err((StatusCode::NOT_FOUND, "Task not found").into_response())
+            }
+            Err(e) => {
+                return Err(err_to_reply(
+                    e.into(),
+                    &name,
+                    "Failed to get task",
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                ))
+            }
+        };
+        TaskForm::load(&mut lock, Some(task))
+    }
+
+    pub async fn delete(
+        State(p): AppState,
+        Path(name): Path<String>,
+    ) -> Result<impl IntoResponse, Response> {
+        match p.db_name(db::delete_task, &name).await {
+            Ok(v) => {
+                if v {
+                    if let Err(e) = p.cancel(&name).await {
+                        return Err(err_to_reply(
+                            e,
+                            &name,
+                            "Failed to issue cancel",
+                            StatusCode::INTERNAL_SERVER_ERROR,
+                        ));
+                    }
+                } else {
+                    return Err((StatusCode::NOT_FOUND, "Task not found").into_response());
+                }
+            }
+            Err(e) => {
+                return Err(err_to_reply(
+                    e.into(),
+                    &name,
+                    "Failed to delete task",
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                ))
+            }
+        };
+
+        Tasks::get(State(p)).await
+    }
+}
+
+#[derive(Template)]
+#[template(path = "files.html")]
+pub struct Files {
+    pub files: Vec<FileInfo>,
+}
+impl Files {
+    fn load(lock: &mut PlayerLock<'_>) -> Result<Self, Response> {
+        let files = db::list_file_info(&lock.lock).map_err(|e| {
+            err_to_reply(
+                e.into(),
+                "List files",
+                "Failed to get files",
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        })?;
+        Ok(Self { files })
+    }
+    pub async fn get(State(p): AppState) -> Result<impl IntoResponse, Response> {
+        Self::load(&mut p.lock().await)
+    }
+    pub async fn post(
+        State(p): AppState,
+        mut form: Multipart,
+    ) -> Result<impl IntoResponse, Response> {
+        while let Some(field) = form.next_field().await.unwrap() {
+            let name = field.name().unwrap().to_string();
+            if name != "file" {
+                continue;
+            };
             let fname = field.file_name().unwrap().to_string();
             let data = field.bytes().await.unwrap();
 
+            // decoding all of it takes a while, so it's done off the async threads
+            let buf = data.clone();
+            let loudness = tokio::task::spawn_blocking(move || measure(buf))
+                .await
+                .map_err(anyhow::Error::from)
+                .and_then(|res| res)
+                .unwrap_or_else(|e| {
+                    warn!("{fname}: failed to measure loudness\n{e:#?}");
+                    None
+                });
+
+            let conn = p.conn.lock().await;
+            // files and playlists share their names
+            if matches!(db::get_playlist(&conn, &fname), Ok(Some(_))) {
+                return Err((StatusCode::BAD_REQUEST, "Name already in use").into_response());
+            }
             if let Err(e) = db::insert_file(
-                &*p.conn.lock().await,
+                &conn,
                 File {
                     name: fname.clone(),
                     data,
+                    gain: 0.0,
+                    loudness,
                 },
             ) {
                 error!("failed to save file: {fname}\n{e:#?}");
@@ -384,11 +1236,16 @@ impl Files {
                     StatusCode::INSUFFICIENT_STORAGE,
                 ));
             }
+            drop(conn);
 
-            // update file stats
+            // update file stats, and decode it once for its length
             let conn = p.conn.clone();
             tokio::spawn(async move {
-                db::update_file_stats(&*conn.lock().await);
+                let conn = conn.lock().await;
+                db::update_file_stats(&conn);
+                if let Err(e) = db::get_file_duration(&conn, &fname) {
+                    warn!("{fname}: failed to get length\n{e:#?}");
+                }
             });
 
             return updated_files(p).await;
@@ -401,12 +1258,7 @@ impl Files {
     ) -> Result<impl IntoResponse, Response> {
         if let Err(e) = db::delete_file(&*p.conn.lock().await, &fname) {
             error!("failed to delete file: {fname}\n{e:#?}");
-            return Err(err_to_reply(
-                e.into(),
-                &fname,
-                "Failed to delete file",
-                StatusCode::NOT_FOUND,
-            ));
+            return Err((StatusCode::BAD_REQUEST, e.to_string()).into_response());
         }
 
         // update file stats
@@ -417,17 +1269,448 @@ impl Files {
 
         updated_files(p).await
     }
+    /// the gain of a file, in dB
+    pub async fn put_gain(
+        State(p): AppState,
+        Path(fname): Path<String>,
+        Form(f): Form<HashMap<String, String>>,
+    ) -> Result<impl IntoResponse, Response> {
+        let gain =
+            parse_gain(&f).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()).into_response())?;
+        let mut lock = p.lock().await;
+        match db::set_file_gain(&lock.lock, &fname, gain) {
+            Ok(true) => info!("{fname}: gain set to {gain} dB"),
+            Ok(false) => return Err((StatusCode::NOT_FOUND, "File not found").into_response()),
+            Err(e) => {
+                return Err(err_to_reply(
+                    e.into(),
+                    &fname,
+                    "Failed to set gain",
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                ))
+            }
+        }
+        Self::load(&mut lock)
+    }
+}
+
+#[derive(Template)]
+#[template(path = "loudness.html")]
+pub struct LoudnessTarget {
+    /// LUFS, `None` if normalization is turned off
+    pub target: Option<f64>,
+}
+impl LoudnessTarget {
+    fn load(lock: &mut PlayerLock<'_>) -> Result<Self, Response> {
+        let target = db::get_target_loudness(&lock.lock).map_err(|e| {
+            err_to_reply(
+                e,
+                "Get target loudness",
+                "Failed to get target loudness",
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        })?;
+        Ok(Self { target })
+    }
+
+    pub async fn get(State(p): AppState) -> Result<impl IntoResponse, Response> {
+        Self::load(&mut p.lock().await)
+    }
+    /// an empty `target` turns normalization off
+    pub async fn put(
+        State(p): AppState,
+        Form(f): Form<HashMap<String, String>>,
+    ) -> Result<impl IntoResponse, Response> {
+        let target = match f.get("target").filter(|s| !s.is_empty()) {
+            Some(s) => match s.parse() {
+                Ok(target) => Some(target),
+                Err(_) => {
+                    return Err((StatusCode::BAD_REQUEST, "Invalid value `target`").into_response())
+                }
+            },
+            None => None,
+        };
+        let mut lock = p.lock().await;
+        if let Err(e) = db::set_target_loudness(&lock.lock, target) {
+            error!("target loudness: {e:#?}");
+            return Err((StatusCode::BAD_REQUEST, e.to_string()).into_response());
+        }
+        info!("target loudness: {target:?} LUFS");
+        Self::load(&mut lock)
+    }
+}
+
+#[derive(Template)]
+#[template(path = "calendar.html")]
+pub struct Calendar {
+    pub entries: Vec<Entry>,
+}
+impl Calendar {
+    pub async fn get(State(p): AppState) -> Result<impl IntoResponse, Response> {
+        let entries = p.lock().await.list_calendar()?;
+        Ok(Self { entries })
+    }
+    pub async fn post(
+        State(p): AppState,
+        Form(f): Form<HashMap<String, String>>,
+    ) -> Result<impl IntoResponse, Response> {
+        let res = Self::post_inner(p.clone(), f).await;
+        if let Err(e) = res {
+            error!("post calendar: {e:#?}");
+            return Err((StatusCode::BAD_REQUEST, e.to_string()).into_response());
+        }
+
+        Calendar::get(State(p)).await
+    }
+    async fn post_inner(p: Player, mut f: HashMap<String, String>) -> anyhow::Result<()> {
+        let Some(name) = f.remove("name").filter(|s| !s.trim().is_empty()) else {
+            anyhow::bail!("Missing value `name`")
+        };
+        let Some(Ok(kind)) = f.get("kind").map(|s| s.parse::<calendar::Kind>()) else {
+            anyhow::bail!("Missing or invalid value `kind`")
+        };
+        let Some(Ok(start)) = f.get("start").map(|s| s.parse::<NaiveDate>()) else {
+            anyhow::bail!("Missing or invalid value `start`")
+        };
+        let end = match f.get("end").filter(|s| !s.is_empty()) {
+            Some(s) => s
+                .parse::<NaiveDate>()
+                .map_err(|_| anyhow::anyhow!("Invalid value `end`"))?,
+            None => start,
+        };
+        if end < start {
+            anyhow::bail!("`end` can't be before `start`")
+        }
+
+        let entry = Entry {
+            id: 0,
+            kind,
+            name,
+            start,
+            end,
+        };
+        db::insert_calendar_entry(&*p.conn.lock().await, &entry).map_err(db::db_err)?;
+        Ok(())
+    }
+    pub async fn delete(
+        State(p): AppState,
+        Path(id): Path<i64>,
+    ) -> Result<impl IntoResponse, Response> {
+        match db::delete_calendar_entry(&*p.conn.lock().await, id) {
+            Ok(true) => (),
+            Ok(false) => return Err((StatusCode::NOT_FOUND, "Entry not found").into_response()),
+            Err(e) => {
+                return Err(err_to_reply(
+                    e.into(),
+                    &id.to_string(),
+                    "Failed to delete calendar entry",
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                ))
+            }
+        }
+
+        Calendar::get(State(p)).await
+    }
+}
+
+#[derive(Template)]
+#[template(path = "profiles.html")]
+pub struct Profiles {
+    pub profiles: Vec<String>,
+    pub active: Option<String>,
+    pub overrides: Vec<(NaiveDate, String)>,
+}
+impl Profiles {
+    fn load(lock: &mut PlayerLock<'_>) -> Result<Self, Response> {
+        let res = (|| -> rusqlite::Result<_> {
+            Ok(Self {
+                profiles: db::list_profiles(&lock.lock)?,
+                active: db::get_active_profile(&lock.lock)?,
+                overrides: db::list_profile_overrides(&lock.lock)?,
+            })
+        })();
+        res.map_err(|e| {
+            err_to_reply(
+                e.into(),
+                "List profiles",
+                "Failed to get profiles",
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        })
+    }
+    pub fn is_active(&self, name: &str) -> bool {
+        self.active.as_deref() == Some(name)
+    }
+
+    pub async fn get(State(p): AppState) -> Result<impl IntoResponse, Response> {
+        Self::load(&mut p.lock().await)
+    }
+    pub async fn post(
+        State(p): AppState,
+        Form(mut f): Form<HashMap<String, String>>,
+    ) -> Result<impl IntoResponse, Response> {
+        let Some(name) = f.remove("name").filter(|s| !s.trim().is_empty()) else {
+            return Err((StatusCode::BAD_REQUEST, "Missing value `name`").into_response());
+        };
+        let mut lock = p.lock().await;
+        if let Err(e) = db::insert_profile(&lock.lock, &name) {
+            error!("post profile: {e:#?}");
+            return Err((StatusCode::BAD_REQUEST, db::db_err(e).to_string()).into_response());
+        }
+        Self::load(&mut lock)
+    }
+    pub async fn delete(
+        State(p): AppState,
+        Path(name): Path<String>,
+    ) -> Result<impl IntoResponse, Response> {
+        let mut lock = p.lock().await;
+        match db::delete_profile(&lock.lock, &name) {
+            Ok(true) => (),
+            Ok(false) => return Err((StatusCode::NOT_FOUND, "Profile not found").into_response()),
+            Err(e) => {
+                error!("delete profile: {e:#?}");
+                return Err((StatusCode::BAD_REQUEST, e.to_string()).into_response());
+            }
+        }
+        Self::load(&mut lock)
+    }
+    /// make a profile the active one
+    pub async fn put(
+        State(p): AppState,
+        Path(name): Path<String>,
+    ) -> Result<impl IntoResponse, Response> {
+        let mut lock = p.lock().await;
+        if let Err(e) = db::set_active_profile(&lock.lock, Some(&name)) {
+            error!("activate profile: {e:#?}");
+            return Err((StatusCode::BAD_REQUEST, e.to_string()).into_response());
+        }
+        info!("active profile: {name}");
+        Self::load(&mut lock)
+    }
+
+    pub async fn post_override(
+        State(p): AppState,
+        Form(f): Form<HashMap<String, String>>,
+    ) -> Result<impl IntoResponse, Response> {
+        let Some(Ok(date)) = f.get("date").map(|s| s.parse::<NaiveDate>()) else {
+            return Err((StatusCode::BAD_REQUEST, "Missing or invalid value `date`").into_response());
+        };
+        let mut lock = p.lock().await;
+        let profile = match f.get("profile") {
+            Some(profile) if db::list_profiles(&lock.lock).is_ok_and(|l| l.contains(profile)) => {
+                profile
+            }
+            _ => return Err((StatusCode::BAD_REQUEST, "Profile not found").into_response()),
+        };
+        if let Err(e) = db::insert_profile_override(&lock.lock, date, profile) {
+            return Err(err_to_reply(
+                e.into(),
+                &date.to_string(),
+                "Failed to save override",
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ));
+        }
+        Self::load(&mut lock)
+    }
+    pub async fn delete_override(
+        State(p): AppState,
+        Path(date): Path<NaiveDate>,
+    ) -> Result<impl IntoResponse, Response> {
+        let mut lock = p.lock().await;
+        match db::delete_profile_override(&lock.lock, date) {
+            Ok(true) => (),
+            Ok(false) => return Err((StatusCode::NOT_FOUND, "Override not found").into_response()),
+            Err(e) => {
+                return Err(err_to_reply(
+                    e.into(),
+                    &date.to_string(),
+                    "Failed to delete override",
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                ))
+            }
+        }
+        Self::load(&mut lock)
+    }
+}
+
+#[derive(Template)]
+#[template(path = "playlists.html")]
+pub struct Playlists {
+    pub playlists: Vec<Playlist>,
+    pub files: Vec<String>,
+}
+impl Playlists {
+    fn load(lock: &mut PlayerLock<'_>) -> Result<Self, Response> {
+        Ok(Self {
+            playlists: lock.list_playlists()?,
+            files: lock.list_files()?,
+        })
+    }
+    /// the playlists, and the file picker of the task form to pick them
+    fn updated(lock: &mut PlayerLock<'_>) -> Result<String, Response> {
+        let playlists = Self::load(lock)?;
+        let picker = FilePicker {
+            files: playlists.files.clone(),
+            playlists: playlists.playlists.iter().map(|p| p.name.clone()).collect(),
+            file: String::new(),
+        };
+        render_all(&[&playlists, &picker])
+    }
+
+    pub async fn get(State(p): AppState) -> Result<impl IntoResponse, Response> {
+        Self::load(&mut p.lock().await)
+    }
+    pub async fn post(
+        State(p): AppState,
+        Form(mut f): Form<HashMap<String, String>>,
+    ) -> Result<impl IntoResponse, Response> {
+        let Some(name) = f.remove("name").filter(|s| !s.trim().is_empty()) else {
+            return Err((StatusCode::BAD_REQUEST, "Missing value `name`").into_response());
+        };
+        let mut lock = p.lock().await;
+        if let Err(e) = db::insert_playlist(&lock.lock, &name) {
+            error!("post playlist: {e:#?}");
+            return Err((StatusCode::BAD_REQUEST, e.to_string()).into_response());
+        }
+        Self::updated(&mut lock)
+    }
+    pub async fn delete(
+        State(p): AppState,
+        Path(name): Path<String>,
+    ) -> Result<impl IntoResponse, Response> {
+        let mut lock = p.lock().await;
+        match db::delete_playlist(&lock.lock, &name) {
+            Ok(true) => (),
+            Ok(false) => return Err((StatusCode::NOT_FOUND, "Playlist not found").into_response()),
+            Err(e) => {
+                error!("delete playlist: {e:#?}");
+                return Err((StatusCode::BAD_REQUEST, e.to_string()).into_response());
+            }
+        }
+        Self::updated(&mut lock)
+    }
+    /// add a file to the end of a playlist
+    pub async fn post_item(
+        State(p): AppState,
+        Path(name): Path<String>,
+        Form(f): Form<HashMap<String, String>>,
+    ) -> Result<impl IntoResponse, Response> {
+        let Some(file_name) = f.get("file_name") else {
+            return Err((StatusCode::BAD_REQUEST, "Missing value `file_name`").into_response());
+        };
+        let gap = match f
+            .get("gap")
+            .filter(|s| !s.is_empty())
+            .map(|s| s.parse::<f64>())
+        {
+            None => 0.0,
+            Some(Ok(gap)) if gap >= 0.0 && gap.is_finite() => gap,
+            Some(_) => return Err((StatusCode::BAD_REQUEST, "Invalid value `gap`").into_response()),
+        };
+        let mut lock = p.lock().await;
+        if let Err(e) = db::insert_playlist_item(&lock.lock, &name, file_name, gap) {
+            error!("{name}: post playlist item: {e:#?}");
+            return Err((StatusCode::BAD_REQUEST, e.to_string()).into_response());
+        }
+        Self::load(&mut lock)
+    }
+    pub async fn delete_item(
+        State(p): AppState,
+        Path((name, id)): Path<(String, i64)>,
+    ) -> Result<impl IntoResponse, Response> {
+        let mut lock = p.lock().await;
+        match db::delete_playlist_item(&lock.lock, &name, id) {
+            Ok(true) => (),
+            Ok(false) => return Err((StatusCode::NOT_FOUND, "Item not found").into_response()),
+            Err(e) => {
+                return Err(err_to_reply(
+                    e.into(),
+                    &name,
+                    "Failed to delete playlist item",
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                ))
+            }
+        }
+        Self::load(&mut lock)
+    }
+}
+
+/// the tasks relative ones can follow
+fn anchor_names(lock: &mut PlayerLock<'_>) -> Result<Vec<String>, Response> {
+    Ok(lock
+        .list_tasks()?
+        .into_iter()
+        .filter(|t| !t.is_now() && t.get_anchor().is_none())
+        .map(|t| t.get_name().to_owned())
+        .collect())
+}
+
+fn playlist_names(lock: &mut PlayerLock<'_>) -> Result<Vec<String>, Response> {
+    Ok(lock.list_playlists()?.into_iter().map(|p| p.name).collect())
+}
+
+/// look for plays of `task` overlapping with others, and reject it if configured so.
+/// returns whether there were any
+fn check_conflicts(
+    conn: &Connection,
+    task: &Task,
+    replaces: Option<&str>,
+    now: DateTime<Local>,
+) -> anyhow::Result<bool> {
+    let conflicts = scheduler::conflicts_with(conn, task, replaces, now)?;
+    let Some(c) = conflicts.first() else {
+        return Ok(false);
+    };
+    let msg = format!(
+        "`{}` would start {:.1}s late at {}, because `{}` is still playing ({} conflicts)",
+        c.second,
+        c.delay,
+        c.time.format(DATEFMT),
+        c.first,
+        conflicts.len()
+    );
+    if ConflictRule::from_env() == ConflictRule::Reject {
+        anyhow::bail!(msg)
+    }
+    warn!("{}: {msg}", task.get_name());
+    Ok(true)
+}
+
+/// make the frontend warn about conflicts
+fn with_conflict_warning(mut res: Response, conflicts: bool) -> Response {
+    if conflicts {
+        res.headers_mut()
+            .insert("HX-Trigger", HeaderValue::from_static("conflict"));
+    }
+    res
+}
+
+/// render multiple templates into one response, eg. for out of band swaps
+fn render_all(templates: &[&dyn askama::DynTemplate]) -> Result<String, Response> {
+    let mut res = String::new();
+    for t in templates {
+        res += &t.dyn_render().map_err(|e| {
+            err_to_reply(
+                e.into(),
+                "",
+                "Failed to render",
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        })?;
+        res += "\n\n";
+    }
+    Ok(res)
 }
 
 /// to be sent back when the files were mutated, as these depend on that data
 async fn updated_files(p: Player) -> Result<impl IntoResponse, Response> {
-    let files = p.lock().await.list_files()?;
+    let mut lock = p.lock().await;
+    let files = lock.list_files()?;
+    let playlists = playlist_names(&mut lock)?;
+    let file_list = Files::load(&mut lock)?;
+    drop(lock);
 
-    let file_list = Files {
-        files: files.clone(),
-    }
-    .render()
-    .map_err(|e| {
+    let file_list = file_list.render().map_err(|e| {
         err_to_reply(
             e.into(),
             "",
@@ -436,7 +1719,13 @@ async fn updated_files(p: Player) -> Result<impl IntoResponse, Response> {
         )
     })?;
 
-    let form = FilePicker { files }.render().map_err(|e| {
+    let form = FilePicker {
+        files,
+        playlists,
+        file: String::new(),
+    }
+    .render()
+    .map_err(|e| {
         err_to_reply(
             e.into(),
             "",
@@ -466,6 +1755,136 @@ fn query_times(q: &HashMap<String, String>, times: &mut Vec<Option<DateTime<Loca
     }
 }
 
+/// extract the checked days from params formatted: day-{weekday}
+/// extract the checked zones from query params formatted: zone-{name}
+fn query_zones(q: &HashMap<String, String>) -> Vec<String> {
+    zones::names()
+        .into_iter()
+        .filter(|z| q.contains_key(&format!("zone-{z}")))
+        .collect()
+}
+
+fn query_days(q: &HashMap<String, String>) -> Vec<Weekday> {
+    all_days()
+        .into_iter()
+        .filter(|d| q.contains_key(&format!("day-{d}")))
+        .collect()
+}
+
+/// the longest range of a timeline
+const MAX_RANGE_DAYS: i64 = 31;
+
+/// a time range from params: from={rfc3339|date}, to={rfc3339|date} or days={n}.
+/// starts at `now` and lasts `default_days` by default
+pub fn query_range(
+    q: &HashMap<String, String>,
+    default_days: i64,
+    now: DateTime<Local>,
+) -> anyhow::Result<(DateTime<Local>, DateTime<Local>)> {
+    let parse = |key: &str| -> anyhow::Result<Option<DateTime<Local>>> {
+        let Some(s) = q.get(key) else {
+            return Ok(None);
+        };
+        if let Ok(time) = DateTime::parse_from_rfc3339(s) {
+            return Ok(Some(time.with_timezone(&Local)));
+        }
+        match s.parse::<NaiveDate>().ok().and_then(|d| {
+            d.and_time(NaiveTime::MIN)
+                .and_local_timezone(Local)
+                .earliest()
+        }) {
+            Some(time) => Ok(Some(time)),
+            None => anyhow::bail!("Invalid value `{key}`"),
+        }
+    };
+
+    let from = parse("from")?.unwrap_or(now);
+    let to = match parse("to")? {
+        Some(to) => to,
+        None => {
+            // checked before it's added, a huge number would overflow
+            let days = match q.get("days").map(|s| s.parse::<i64>()) {
+                Some(Ok(days)) if days > MAX_RANGE_DAYS => {
+                    anyhow::bail!("The range can be at most {MAX_RANGE_DAYS} days")
+                }
+                Some(Ok(days)) if days > 0 => days,
+                Some(_) => anyhow::bail!("Invalid value `days`"),
+                None => default_days,
+            };
+            match from.checked_add_signed(chrono::Duration::days(days)) {
+                Some(to) => to,
+                None => anyhow::bail!("Invalid value `from`"),
+            }
+        }
+    };
+
+    if to <= from {
+        anyhow::bail!("`to` has to be after `from`")
+    }
+    if to - from > chrono::Duration::days(MAX_RANGE_DAYS) {
+        anyhow::bail!("The range can be at most {MAX_RANGE_DAYS} days")
+    }
+    Ok((from, to))
+}
+
+/// the signed offset of a relative task from params: offset={minutes}, direction={after|before}
+fn parse_offset(f: &HashMap<String, String>) -> Option<i64> {
+    let minutes = f.get("offset")?.parse::<i64>().ok().filter(|m| *m >= 0)?;
+    match f.get("direction").map(String::as_str).unwrap_or("after") {
+        "after" => Some(minutes),
+        "before" => Some(-minutes),
+        _ => None,
+    }
+}
+
+/// an optional date from params, empty counts as missing
+fn parse_date(f: &HashMap<String, String>, key: &str) -> anyhow::Result<Option<NaiveDate>> {
+    match f.get(key).filter(|s| !s.is_empty()) {
+        Some(s) => match s.parse() {
+            Ok(date) => Ok(Some(date)),
+            Err(_) => anyhow::bail!("Invalid value `{key}`"),
+        },
+        None => Ok(None),
+    }
+}
+
+/// a gain in dB from params, empty counts as 0
+fn parse_gain(f: &HashMap<String, String>) -> anyhow::Result<f32> {
+    match f.get("gain").filter(|s| !s.is_empty()) {
+        Some(s) => match s.parse() {
+            Ok(gain) if GAIN_RANGE.contains(&gain) => Ok(gain),
+            _ => anyhow::bail!("Invalid value `gain`"),
+        },
+        None => Ok(0.0),
+    }
+}
+
+/// a fade in seconds from params, empty counts as 0
+fn parse_fade(f: &HashMap<String, String>, key: &str) -> anyhow::Result<f32> {
+    match f.get(key).filter(|s| !s.is_empty()) {
+        Some(s) => match s.parse() {
+            Ok(secs) if FADE_RANGE.contains(&secs) => Ok(secs),
+            _ => anyhow::bail!("Invalid value `{key}`"),
+        },
+        None => Ok(0.0),
+    }
+}
+
+/// the missed policy from params: missed={drop|play|notify}, grace={minutes}
+fn parse_missed(f: &HashMap<String, String>) -> anyhow::Result<Missed> {
+    Ok(match f.get("missed").map(String::as_str).unwrap_or("drop") {
+        "drop" => Missed::Drop,
+        "play" => {
+            let Some(Ok(grace)) = f.get("grace").map(|s| s.parse::<u32>()) else {
+                anyhow::bail!("Missing or invalid value `grace`")
+            };
+            Missed::Play { grace }
+        }
+        "notify" => Missed::Notify,
+        _ => anyhow::bail!("Invalid value for `missed`"),
+    })
+}
+
 pub use filters::dur_human;
 mod filters {
     use std::time::Duration;
@@ -473,7 +1892,12 @@ mod filters {
     use askama::Result;
     use chrono::{DateTime, Local, NaiveTime};
 
-    use crate::{player::NowPlaying, Task};
+    use crate::{
+        db::TIMEFMT,
+        player::NowPlaying,
+        scheduler::{duration_until_next, parse_cron},
+        Action, Anchor, Exception, Task,
+    };
 
     pub fn datefmt(d: &DateTime<Local>) -> Result<String> {
         Ok(d.format(super::DATEFMT).to_string())
@@ -488,32 +1912,87 @@ mod filters {
         let fmt = if secs >= 60 * 60 { "%H:%M:%S" } else { "%M:%S" };
         Ok(time.format(fmt).to_string())
     }
+    /// seconds, like `durfmt`
+    pub fn secsfmt(secs: &f64) -> Result<String> {
+        durfmt(Duration::from_secs_f64(secs.max(0.0)))
+    }
     pub fn task_timefmt(task: &Task) -> Result<String> {
         let s = match task {
             Task::Now { .. } => return Ok("".into()),
             Task::Scheduled { time, .. } => datefmt(time)?.replace('T', " "),
-            Task::Recurring { .. } => task.time_to_str().unwrap().replace(';', ", "),
+            Task::Recurring { days, .. } => {
+                let times = task.time_to_str().unwrap().replace(';', ", ");
+                if days.len() == 7 {
+                    times
+                } else {
+                    let days = days
+                        .iter()
+                        .map(|d| super::WEEKDAY_NAMES[d.num_days_from_monday() as usize])
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    format!("{times} ({days})")
+                }
+            }
+            Task::Cron { expr, .. } => expr.to_owned(),
+        };
+        Ok(s)
+    }
+    /// the first and last day of a recurring task, empty if it rings forever
+    /// where a relative task is compared to the task it follows
+    pub fn anchor_fmt(anchor: &Anchor) -> Result<String> {
+        let minutes = anchor.offset / 60;
+        let side = if minutes < 0 { "előtt" } else { "után" };
+        Ok(format!("{} {} perccel {side}", anchor.task, minutes.abs()))
+    }
+    pub fn validity_fmt(task: &Task) -> Result<String> {
+        let s = match (task.get_valid_from(), task.get_valid_until()) {
+            (None, None) => String::new(),
+            (Some(from), None) => format!("{from} –"),
+            (None, Some(until)) => format!("– {until}"),
+            (Some(from), Some(until)) => format!("{from} – {until}"),
         };
         Ok(s)
     }
-    pub fn task_elapsed(task: &Task) -> Result<(String, u32)> {
+    pub fn exception_fmt(e: &Exception) -> Result<String> {
+        let action = match &e.action {
+            Action::Skip => "kimarad".to_string(),
+            Action::Move { to } => format!("áthelyezve: {}", to.format(TIMEFMT)),
+            Action::Replace { file_name } => format!("fájl: {file_name}"),
+        };
+        Ok(format!("{} {}: {action}", e.date, e.time.format(TIMEFMT)))
+    }
+    pub fn task_elapsed(
+        task: &Task,
+        exceptions: &[Exception],
+        now: DateTime<Local>,
+    ) -> Result<(String, u32)> {
         let next: chrono::Duration = match task {
             Task::Now { .. } => return Ok(("".into(), 0)),
-            Task::Scheduled { time, .. } => *time - Local::now(),
-            Task::Recurring { time: times, .. } => {
-                let now = Local::now().time();
-                times
+            Task::Scheduled { time, .. } => *time - now,
+            Task::Recurring {
+                name,
+                time: times,
+                days,
+                valid_from,
+                valid_until,
+                ..
+            } => {
+                let exceptions = exceptions
                     .iter()
-                    .map(|t| *t - now)
-                    .map(|t| {
-                        if t.num_milliseconds() < 0 {
-                            t + chrono::Duration::days(1)
-                        } else {
-                            t
-                        }
-                    })
-                    .min()
-                    .unwrap()
+                    .filter(|e| &e.task == name)
+                    .cloned()
+                    .collect::<Vec<_>>();
+                match duration_until_next(now, times, days, &exceptions, *valid_from, *valid_until)
+                {
+                    Some((next, _)) => next,
+                    None => return Ok(("lejárt".into(), 0)),
+                }
+            }
+            Task::Cron { expr, .. } => {
+                match parse_cron(expr).map(|c| c.find_next_occurrence(&now, false)) {
+                    Ok(Ok(next)) => next - now,
+                    _ => return Ok(("".into(), 0)),
+                }
             }
         };
         Ok(dur_human(&next))
@@ -564,7 +2043,7 @@ mod filters {
         unreachable!("u32::MAX")
     }
 
-    pub fn has_len(np: &Option<NowPlaying>) -> Result<bool> {
-        Ok(np.as_ref().is_some_and(|n| n.len.is_some()))
+    pub fn has_len(np: &[(String, NowPlaying)]) -> Result<bool> {
+        Ok(np.iter().any(|(_, n)| n.len.is_some()))
     }
 }
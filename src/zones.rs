@@ -0,0 +1,226 @@
+// where plays go: outputs are audio devices, zones are groups of speakers wired to some
+// channels of an output. configured with `$OUTPUTS`, eg. `pa=USB Audio;studio=`
+// (empty for the default device, `null:` to play on nothing, `wav:DIR` to record to DIR),
+// and `$ZONES`, eg. `hallways=0;classrooms=1;studio=studio:0,1`
+// (zones without an output are on the first one)
+use serde::Serialize;
+use std::{env::var, sync::OnceLock};
+
+/// a single output on the default device
+const DEFAULT_OUTPUTS: &str = "main=";
+/// the original wiring: every play on the left channel, important ones on the right one too
+const DEFAULT_ZONES: &str = "main=0;priority=1";
+/// the least number of channels played, even if no zone uses them
+const MIN_CHANNELS: u16 = 2;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Output {
+    pub name: String,
+    /// the name of the audio device, `None` for the default one
+    pub device: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Zone {
+    pub name: String,
+    /// the name of the output it's on
+    pub output: String,
+    /// bit `n` is channel `n`
+    pub mask: u32,
+}
+
+/// where to play something on a single output
+#[derive(Debug, Clone, PartialEq)]
+pub struct Route {
+    pub output: &'static str,
+    pub mask: u32,
+    /// the number of channels to play
+    pub channels: u16,
+}
+
+/// the configured outputs, never empty
+pub fn outputs() -> &'static [Output] {
+    static OUTPUTS: OnceLock<Vec<Output>> = OnceLock::new();
+    OUTPUTS.get_or_init(|| {
+        let outputs = var("OUTPUTS").ok().and_then(|s| match parse_outputs(&s) {
+            Ok(outputs) => Some(outputs),
+            Err(e) => {
+                error!("invalid $OUTPUTS, using the default one: {e}");
+                None
+            }
+        });
+        outputs.unwrap_or_else(|| parse_outputs(DEFAULT_OUTPUTS).unwrap())
+    })
+}
+
+/// the configured zones, never empty
+pub fn get() -> &'static [Zone] {
+    static ZONES: OnceLock<Vec<Zone>> = OnceLock::new();
+    ZONES.get_or_init(|| {
+        let zones = var("ZONES").ok().and_then(|s| match parse(&s, outputs()) {
+            Ok(zones) => Some(zones),
+            Err(e) => {
+                error!("invalid $ZONES, using the default ones: {e}");
+                None
+            }
+        });
+        zones.unwrap_or_else(|| parse(DEFAULT_ZONES, outputs()).unwrap())
+    })
+}
+
+pub fn names() -> Vec<String> {
+    get().iter().map(|z| z.name.clone()).collect()
+}
+
+/// the number of channels needed to reach every zone of an output
+pub fn channels(output: &str) -> u16 {
+    let mask = get()
+        .iter()
+        .filter(|z| z.output == output)
+        .fold(0, |mask, z| mask | z.mask);
+    ((u32::BITS - mask.leading_zeros()) as u16).max(MIN_CHANNELS)
+}
+
+/// the channels of the zones on each output, the first zone's if there are none.
+/// zones removed from the config are skipped
+pub fn routes(names: &[String]) -> Vec<Route> {
+    let zones = get();
+    let mut selected: Vec<&Zone> = names
+        .iter()
+        .filter_map(|name| {
+            let zone = zones.iter().find(|z| &z.name == name);
+            if zone.is_none() {
+                warn!("unknown zone: {name}");
+            }
+            zone
+        })
+        .collect();
+    if selected.is_empty() {
+        selected.push(&zones[0]);
+    }
+
+    let mut routes: Vec<Route> = Vec::new();
+    for zone in selected {
+        match routes.iter_mut().find(|r| r.output == zone.output) {
+            Some(route) => route.mask |= zone.mask,
+            None => routes.push(Route {
+                output: &zone.output,
+                mask: zone.mask,
+                channels: channels(&zone.output),
+            }),
+        }
+    }
+    routes
+}
+
+/// fails if any of the zones doesn't exist
+pub fn check(names: &[String]) -> anyhow::Result<()> {
+    match names
+        .iter()
+        .find(|name| !get().iter().any(|z| z.name == **name))
+    {
+        Some(name) => anyhow::bail!("Zone not found: {name}"),
+        None => Ok(()),
+    }
+}
+
+fn parse_outputs(s: &str) -> anyhow::Result<Vec<Output>> {
+    let mut outputs: Vec<Output> = Vec::new();
+    for output in s.split(';').filter(|o| !o.trim().is_empty()) {
+        let Some((name, device)) = output.split_once('=') else {
+            anyhow::bail!("`{output}`: expected `name=device`")
+        };
+        let name = name.trim();
+        if name.is_empty() || outputs.iter().any(|o| o.name == name) {
+            anyhow::bail!("`{output}`: the name must be unique and not empty")
+        }
+        let device = device.trim();
+        outputs.push(Output {
+            name: name.to_owned(),
+            device: (!device.is_empty()).then(|| device.to_owned()),
+        });
+    }
+    if outputs.is_empty() {
+        anyhow::bail!("no outputs")
+    }
+    Ok(outputs)
+}
+
+fn parse(s: &str, outputs: &[Output]) -> anyhow::Result<Vec<Zone>> {
+    let mut zones: Vec<Zone> = Vec::new();
+    for zone in s.split(';').filter(|z| !z.trim().is_empty()) {
+        let Some((name, channels)) = zone.split_once('=') else {
+            anyhow::bail!("`{zone}`: expected `name=channels`")
+        };
+        let name = name.trim();
+        if name.is_empty() || zones.iter().any(|z| z.name == name) {
+            anyhow::bail!("`{zone}`: the name must be unique and not empty")
+        }
+        let (output, channels) = match channels.split_once(':') {
+            Some((output, channels)) => match outputs.iter().find(|o| o.name == output.trim()) {
+                Some(output) => (output, channels),
+                None => anyhow::bail!("`{zone}`: unknown output `{output}`"),
+            },
+            None => (&outputs[0], channels),
+        };
+        let mut mask = 0u32;
+        for channel in channels.split(',') {
+            match channel.trim().parse::<u32>() {
+                Ok(c) if c < u32::BITS => mask |= 1 << c,
+                _ => anyhow::bail!("`{zone}`: invalid channel `{channel}`"),
+            }
+        }
+        zones.push(Zone {
+            name: name.to_owned(),
+            output: output.name.clone(),
+            mask,
+        });
+    }
+    if zones.is_empty() {
+        anyhow::bail!("no zones")
+    }
+    Ok(zones)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_channel_lists() {
+        let outputs = parse_outputs("pa=USB Audio; studio=").unwrap();
+        assert_eq!(outputs[0].device.as_deref(), Some("USB Audio"));
+        assert_eq!(outputs[1].device, None);
+
+        let zones = parse(
+            "hallways=0; classrooms=1 ;yard=0,2;studio=studio:1",
+            &outputs,
+        )
+        .unwrap();
+        let masks: Vec<_> = zones
+            .iter()
+            .map(|z| (z.name.as_str(), z.output.as_str(), z.mask))
+            .collect();
+        assert_eq!(
+            masks,
+            [
+                ("hallways", "pa", 0b001),
+                ("classrooms", "pa", 0b010),
+                ("yard", "pa", 0b101),
+                ("studio", "studio", 0b010)
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid() {
+        let outputs = parse_outputs(DEFAULT_OUTPUTS).unwrap();
+        assert!(parse("", &outputs).is_err());
+        assert!(parse("yard", &outputs).is_err());
+        assert!(parse("yard=x", &outputs).is_err());
+        assert!(parse("yard=32", &outputs).is_err());
+        assert!(parse("yard=0;yard=1", &outputs).is_err());
+        assert!(parse("yard=studio:0", &outputs).is_err());
+        assert!(parse_outputs("pa=;pa=").is_err());
+    }
+}